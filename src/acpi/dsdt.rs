@@ -0,0 +1,90 @@
+use acpi::SdtHeader;
+
+/// AML opcodes used by the `\_S5` object
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const BYTE_PREFIX: u8 = 0x0a;
+const ROOT_CHAR: u8 = b'\\';
+
+/// The `SLP_TYPa` and `SLP_TYPb` values of a sleep state, to be written to
+/// the PM1a and PM1b control registers.
+#[derive(Debug, Clone, Copy)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Looks for the `\_S5` (soft off) package in the AML of the DSDT. This is a
+/// plain byte scan instead of an AML interpreter, which is enough for the
+/// usual `Name (_S5, Package () { a, b, ... })` definition.
+pub fn find_s5(dsdt: &SdtHeader) -> Option<SleepType> {
+    let aml = dsdt.data();
+    let position = aml.windows(4).position(|name| name == b"_S5_")?;
+
+    // the name must be defined by a `Name` operator, optionally with a root
+    // prefix, followed by a package
+    let defined = (position >= 1 && aml[position - 1] == NAME_OP)
+        || (position >= 2 && aml[position - 2] == NAME_OP && aml[position - 1] == ROOT_CHAR);
+    let mut index = position + 4;
+    if !defined || aml.get(index) != Some(&PACKAGE_OP) {
+        return None;
+    }
+
+    // skip the package length, whose first byte encodes its size in bits 6
+    // and 7, and the number of elements
+    index += 1;
+    let length_bytes = (*aml.get(index)? >> 6) as usize + 1;
+    index += length_bytes + 1;
+
+    // the values are either `Zero`/`One` opcodes, which equal their value,
+    // or byte constants
+    let mut read_value = || {
+        if aml.get(index) == Some(&BYTE_PREFIX) {
+            index += 1;
+        }
+        let value = *aml.get(index)?;
+        index += 1;
+        Some(value)
+    };
+    let a = read_value()?;
+    let b = read_value()?;
+    Some(SleepType { a: a, b: b })
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use core::mem;
+
+    /// Builds a table with the given AML behind the header and passes it to
+    /// `find_s5`. Only `length` is filled in the header.
+    fn find_s5_in(aml: &[u8]) -> Option<SleepType> {
+        let header_size = mem::size_of::<SdtHeader>();
+        let mut bytes = Vec::new();
+        bytes.resize(header_size, 0);
+        bytes.extend_from_slice(aml);
+        let length = bytes.len() as u32;
+        for (index, byte) in bytes[4..8].iter_mut().enumerate() {
+            *byte = (length >> (index * 8)) as u8;
+        }
+        find_s5(unsafe { &*(bytes.as_ptr() as *const SdtHeader) })
+    }
+
+    kernel_test!(dsdt_s5_is_found {
+        // Name (_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })
+        let sleep_type = find_s5_in(b"\x08_S5_\x12\x08\x04\x0a\x05\x0a\x05\x00\x00")
+            .expect("_S5_ not found");
+        assert_eq!((sleep_type.a, sleep_type.b), (5, 5));
+
+        // Name (\_S5, Package (0x04) { Zero, One, ... })
+        let sleep_type = find_s5_in(b"\x08\\_S5_\x12\x06\x04\x00\x01\x00\x00")
+            .expect("_S5_ not found");
+        assert_eq!((sleep_type.a, sleep_type.b), (0, 1));
+
+        // a reference to `_S5_` instead of its definition
+        assert!(find_s5_in(b"\x70_S5_\x12\x06\x04\x00\x01").is_none());
+        // truncated package
+        assert!(find_s5_in(b"\x08_S5_\x12\x06").is_none());
+    });
+}
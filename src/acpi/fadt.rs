@@ -0,0 +1,122 @@
+use acpi::{read, GenericAddress, SdtHeader, ADDRESS_SPACE_IO};
+use core::mem;
+
+/// Offsets of the FADT fields, relative to the end of the header
+const DSDT: usize = 4;
+const SCI_INTERRUPT: usize = 10;
+const SMI_COMMAND: usize = 12;
+const ACPI_ENABLE: usize = 16;
+const PM1A_CONTROL_BLOCK: usize = 28;
+const PM1B_CONTROL_BLOCK: usize = 32;
+const PM_TIMER_BLOCK: usize = 40;
+const CENTURY: usize = 72;
+const BOOT_ARCHITECTURE_FLAGS: usize = 73;
+const FLAGS: usize = 76;
+const RESET_REGISTER: usize = 80;
+const RESET_VALUE: usize = 92;
+const X_DSDT: usize = 104;
+const X_PM1A_CONTROL_BLOCK: usize = 136;
+const X_PM1B_CONTROL_BLOCK: usize = 148;
+
+/// FADT flag: the reset register is supported
+const RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+/// Boot architecture flag: the system has an 8042 keyboard controller
+const HAS_8042: u16 = 1 << 1;
+
+/// The Fixed ACPI Description Table, which describes the fixed power
+/// management hardware.
+#[derive(Debug)]
+pub struct Fadt {
+    /// Physical address of the DSDT
+    pub dsdt: usize,
+    /// Interrupt of the system control interrupt (SCI), as an ISA IRQ
+    pub sci_interrupt: u16,
+    /// I/O port to which `acpi_enable` is written to enable ACPI, 0 if ACPI
+    /// is always enabled
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    /// I/O ports of the PM1 control registers, `pm1b` is 0 if not supported
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    /// I/O port of the ACPI power management timer
+    pub pm_timer_block: u32,
+    /// CMOS register of the RTC century, 0 if not supported
+    pub century_register: u8,
+    /// The system has an 8042 keyboard controller
+    pub has_8042: bool,
+    /// Register and value that reset the system, if supported
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    /// Parses the FADT behind the given header. Fields that are missing in
+    /// older revisions or in a short table are treated as unsupported.
+    pub fn parse(header: &SdtHeader) -> Fadt {
+        let data = header.data();
+        let has = |offset: usize, size: usize| offset + size <= data.len();
+
+        let mut fadt = Fadt {
+            dsdt: 0,
+            sci_interrupt: 0,
+            smi_command: 0,
+            acpi_enable: 0,
+            pm1a_control_block: 0,
+            pm1b_control_block: 0,
+            pm_timer_block: 0,
+            century_register: 0,
+            has_8042: true,
+            reset: None,
+        };
+        if has(DSDT, 4) {
+            fadt.dsdt = read::<u32>(data, DSDT) as usize;
+        }
+        if has(SCI_INTERRUPT, 2) {
+            fadt.sci_interrupt = read(data, SCI_INTERRUPT);
+        }
+        if has(ACPI_ENABLE, 1) {
+            fadt.smi_command = read(data, SMI_COMMAND);
+            fadt.acpi_enable = data[ACPI_ENABLE];
+        }
+        if has(PM1B_CONTROL_BLOCK, 4) {
+            fadt.pm1a_control_block = read(data, PM1A_CONTROL_BLOCK);
+            fadt.pm1b_control_block = read(data, PM1B_CONTROL_BLOCK);
+        }
+        if has(PM_TIMER_BLOCK, 4) {
+            fadt.pm_timer_block = read(data, PM_TIMER_BLOCK);
+        }
+        if has(CENTURY, 1) {
+            fadt.century_register = data[CENTURY];
+        }
+
+        // ACPI 1.0 tables end in front of the boot architecture flags
+        if has(FLAGS, 4) {
+            let flags: u32 = read(data, FLAGS);
+            fadt.has_8042 = read::<u16>(data, BOOT_ARCHITECTURE_FLAGS) & HAS_8042 != 0
+                || header.revision < 3;
+            if flags & RESET_REGISTER_SUPPORTED != 0 && has(RESET_VALUE, 1) {
+                let register = GenericAddress::parse(data, RESET_REGISTER);
+                fadt.reset = Some((register, data[RESET_VALUE]));
+            }
+        }
+
+        // prefer the 64-bit fields of ACPI 2.0 if they are set
+        if has(X_DSDT, mem::size_of::<u64>()) {
+            let x_dsdt = read::<u64>(data, X_DSDT) as usize;
+            if x_dsdt != 0 {
+                fadt.dsdt = x_dsdt;
+            }
+        }
+        if has(X_PM1B_CONTROL_BLOCK, 12) {
+            let pm1a = GenericAddress::parse(data, X_PM1A_CONTROL_BLOCK);
+            let pm1b = GenericAddress::parse(data, X_PM1B_CONTROL_BLOCK);
+            if pm1a.address != 0 && pm1a.address_space == ADDRESS_SPACE_IO {
+                fadt.pm1a_control_block = pm1a.address as u32;
+            }
+            if pm1b.address != 0 && pm1b.address_space == ADDRESS_SPACE_IO {
+                fadt.pm1b_control_block = pm1b.address as u32;
+            }
+        }
+
+        fadt
+    }
+}
@@ -0,0 +1,32 @@
+use acpi::{read, GenericAddress, SdtHeader};
+
+/// The High Precision Event Timer description table.
+#[derive(Debug)]
+pub struct Hpet {
+    /// Hardware revision, comparator count and vendor of the timer block
+    pub event_timer_block_id: u32,
+    /// Location of the timer registers, usually in memory space
+    pub base_address: GenericAddress,
+    /// Sequence number of this timer block
+    pub number: u8,
+    /// Minimum clock ticks for periodic mode without lost interrupts
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// Parses the HPET table behind the given header.
+    pub fn parse(header: &SdtHeader) -> Hpet {
+        let data = header.data();
+        Hpet {
+            event_timer_block_id: read(data, 0),
+            base_address: GenericAddress::parse(data, 4),
+            number: data[16],
+            minimum_tick: read(data, 17),
+        }
+    }
+
+    /// Returns the number of comparators of the timer block.
+    pub fn comparator_count(&self) -> u8 {
+        (((self.event_timer_block_id >> 8) & 0x1f) + 1) as u8
+    }
+}
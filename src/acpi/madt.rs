@@ -0,0 +1,107 @@
+use acpi::{read, SdtHeader};
+use alloc::vec::Vec;
+
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// The Multiple APIC Description Table, which describes the interrupt
+/// controllers of the system.
+#[derive(Debug)]
+pub struct Madt {
+    /// Physical address of the local APICs
+    pub local_apic_address: usize,
+    /// Set if the system also has 8259 PICs, which must be disabled
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+/// A processor and its local APIC.
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// The processor is ready to be used
+    pub enabled: bool,
+}
+
+/// An I/O APIC and the global system interrupts it handles.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: usize,
+    /// First global system interrupt of this I/O APIC
+    pub gsi_base: u32,
+}
+
+/// Describes an ISA IRQ that isn't identity mapped to a global system
+/// interrupt or that doesn't use the ISA defaults (active high, edge
+/// triggered).
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+impl Madt {
+    /// Parses the MADT behind the given header.
+    pub fn parse(header: &SdtHeader) -> Madt {
+        let data = header.data();
+        let mut madt = Madt {
+            local_apic_address: read::<u32>(data, 0) as usize,
+            has_legacy_pics: read::<u32>(data, 4) & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        // the entries start behind the local APIC address and the flags
+        let mut offset = 8;
+        while offset + 2 <= data.len() {
+            let entry_type = data[offset];
+            let length = data[offset + 1] as usize;
+            if length < 2 || offset + length > data.len() {
+                break;
+            }
+            let entry = offset + 2;
+            match entry_type {
+                LOCAL_APIC => madt.processors.push(Processor {
+                    processor_id: data[entry],
+                    apic_id: data[entry + 1],
+                    enabled: read::<u32>(data, entry + 2) & 1 != 0,
+                }),
+                IO_APIC => madt.io_apics.push(IoApic {
+                    id: data[entry],
+                    address: read::<u32>(data, entry + 2) as usize,
+                    gsi_base: read(data, entry + 6),
+                }),
+                INTERRUPT_SOURCE_OVERRIDE => {
+                    let flags: u16 = read(data, entry + 6);
+                    madt.overrides.push(InterruptOverride {
+                        irq: data[entry + 1],
+                        gsi: read(data, entry + 2),
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    })
+                }
+                LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    madt.local_apic_address = read::<u64>(data, entry + 2) as usize;
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+
+        madt
+    }
+
+    /// Returns the override of the given ISA IRQ, if any.
+    pub fn interrupt_override(&self, irq: u8) -> Option<&InterruptOverride> {
+        self.overrides.iter().find(|o| o.irq == irq)
+    }
+}
@@ -0,0 +1,52 @@
+use acpi::{read, SdtHeader};
+use alloc::vec::Vec;
+
+/// Size of a configuration space entry
+const ENTRY_SIZE: usize = 16;
+
+/// The PCI Express memory mapped configuration space description table.
+#[derive(Debug)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+/// The configuration space of the busses of a PCI segment group.
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    /// Physical address of the configuration space of bus 0
+    pub base_address: usize,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    /// Parses the MCFG behind the given header.
+    pub fn parse(header: &SdtHeader) -> Mcfg {
+        let data = header.data();
+        // the entries follow 8 reserved bytes
+        let entries = (8..data.len())
+            .step_by(ENTRY_SIZE)
+            .take_while(|&offset| offset + ENTRY_SIZE <= data.len())
+            .map(|offset| McfgEntry {
+                base_address: read::<u64>(data, offset) as usize,
+                segment_group: read(data, offset + 8),
+                start_bus: data[offset + 10],
+                end_bus: data[offset + 11],
+            })
+            .collect();
+        Mcfg { entries: entries }
+    }
+
+    /// Returns the physical address of the configuration space of a PCI
+    /// function, if it is described by the table.
+    pub fn config_address(&self, segment: u16, bus: u8, device: u8, function: u8) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|e| e.segment_group == segment && bus >= e.start_bus && bus <= e.end_bus)
+            .map(|e| {
+                let bus = (bus - e.start_bus) as usize;
+                e.base_address + (bus << 20 | (device as usize) << 15 | (function as usize) << 12)
+            })
+    }
+}
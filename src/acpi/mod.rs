@@ -0,0 +1,296 @@
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr;
+use core::slice;
+use memory::{self, EntryFlags};
+use spin::Once;
+
+pub mod dsdt;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+pub use self::dsdt::SleepType;
+pub use self::fadt::Fadt;
+pub use self::hpet::Hpet;
+pub use self::madt::Madt;
+pub use self::mcfg::Mcfg;
+
+/// Physical memory area that is searched for the RSDP if the boot loader
+/// doesn't pass a copy of it.
+const BIOS_AREA_START: usize = 0xe0000;
+const BIOS_AREA_END: usize = 0x100000;
+
+/// Multiboot2 tags that contain a copy of the ACPI 1.0 and 2.0 RSDP
+const MULTIBOOT_TAG_END: u32 = 0;
+const MULTIBOOT_TAG_ACPI_OLD: u32 = 14;
+const MULTIBOOT_TAG_ACPI_NEW: u32 = 15;
+
+/// The Root System Description Pointer of ACPI 1.0.
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+}
+
+/// The fields that ACPI 2.0 added to the RSDP.
+#[repr(C, packed)]
+struct RsdpExtension {
+    rsdp: Rsdp,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+impl Rsdp {
+    fn is_valid(&self) -> bool {
+        if &self.signature != b"RSD PTR " || !checksum(self, mem::size_of::<Rsdp>()) {
+            return false;
+        }
+        self.revision < 2 || checksum(self.extension(), mem::size_of::<RsdpExtension>())
+    }
+
+    /// Returns the ACPI 2.0 fields. Only valid if the revision is 2 or more.
+    fn extension(&self) -> &RsdpExtension {
+        unsafe { &*(self as *const Rsdp as *const RsdpExtension) }
+    }
+}
+
+/// The header that every system description table starts with.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Returns the bytes of the whole table, including the header.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) }
+    }
+
+    /// Returns the bytes behind the header.
+    pub fn data(&self) -> &[u8] {
+        &self.bytes()[mem::size_of::<SdtHeader>()..]
+    }
+
+    /// Returns `true` if the bytes of the table sum up to zero.
+    pub fn is_valid(&self) -> bool {
+        self.length as usize >= mem::size_of::<SdtHeader>() && checksum(self, self.length as usize)
+    }
+}
+
+/// Address space of a `GenericAddress`
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+/// The Generic Address Structure, which describes a register in memory or
+/// I/O space.
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Reads the structure at `offset` in `bytes`.
+    fn parse(bytes: &[u8], offset: usize) -> GenericAddress {
+        GenericAddress {
+            address_space: bytes[offset],
+            bit_width: bytes[offset + 1],
+            bit_offset: bytes[offset + 2],
+            access_size: bytes[offset + 3],
+            address: read(bytes, offset + 4),
+        }
+    }
+}
+
+/// The ACPI tables the kernel knows about.
+pub struct Acpi {
+    /// ACPI revision of the RSDP, 0 for ACPI 1.0
+    pub revision: u8,
+    /// Signatures of all tables listed by the RSDT or XSDT
+    pub signatures: Vec<[u8; 4]>,
+    /// The interrupt controller description
+    pub madt: Option<Madt>,
+    /// The fixed hardware description, needed for power management
+    pub fadt: Option<Fadt>,
+    /// The sleep type of the soft off state, from the DSDT
+    pub s5_sleep_type: Option<SleepType>,
+    /// The High Precision Event Timer description
+    pub hpet: Option<Hpet>,
+    /// The PCI Express configuration space description
+    pub mcfg: Option<Mcfg>,
+}
+
+static ACPI: Once<Option<Acpi>> = Once::new();
+
+/// Locates and parses the ACPI tables. Must be called after the heap is
+/// initialized. Returns `None` if there is no valid RSDP.
+pub fn init() -> Option<&'static Acpi> {
+    ACPI.call_once(|| unsafe {
+        find_multiboot_rsdp()
+            .or_else(|| find_bios_rsdp())
+            .map(|rsdp| parse(rsdp))
+    }).as_ref()
+}
+
+/// Returns the tables parsed by `init`.
+pub fn tables() -> Option<&'static Acpi> {
+    ACPI.try().and_then(|acpi| acpi.as_ref())
+}
+
+/// Looks for the copy of the RSDP in the multiboot information, preferring
+/// the ACPI 2.0 one. The multiboot information stays identity mapped.
+unsafe fn find_multiboot_rsdp() -> Option<&'static Rsdp> {
+    let boot_info = memory::boot_info();
+    let mut found: Option<&'static Rsdp> = None;
+
+    // the tags follow the total size and a reserved field, each is 8 byte
+    // aligned and starts with its type and size
+    let mut address = boot_info.start_address() + 8;
+    while address + 8 <= boot_info.end_address() {
+        let tag_type = ptr::read(address as *const u32);
+        let tag_size = ptr::read((address + 4) as *const u32) as usize;
+        if tag_type == MULTIBOOT_TAG_END || tag_size < 8 {
+            break;
+        }
+        let rsdp = &*((address + 8) as *const Rsdp);
+        match tag_type {
+            MULTIBOOT_TAG_ACPI_NEW if rsdp.is_valid() => return Some(rsdp),
+            MULTIBOOT_TAG_ACPI_OLD if rsdp.is_valid() => found = Some(rsdp),
+            _ => {}
+        }
+        address = (address + tag_size + 7) & !7;
+    }
+    found
+}
+
+/// Scans the BIOS read-only memory area for the RSDP signature.
+unsafe fn find_bios_rsdp() -> Option<&'static Rsdp> {
+    let mapped = memory::identity_map_region(
+        BIOS_AREA_START,
+        BIOS_AREA_END - BIOS_AREA_START,
+        EntryFlags::NO_EXECUTE,
+    );
+    if !mapped {
+        return None;
+    }
+    (BIOS_AREA_START..BIOS_AREA_END)
+        .step_by(16)
+        .map(|address| &*(address as *const Rsdp))
+        .find(|rsdp| rsdp.is_valid())
+}
+
+/// Returns `true` if the first `length` bytes of `value` sum up to zero.
+fn checksum<T>(value: &T, length: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(value as *const T as *const u8, length) };
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Reads a `T` from the possibly unaligned `offset` in `bytes`.
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> T {
+    let bytes = &bytes[offset..offset + mem::size_of::<T>()];
+    unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) }
+}
+
+/// Maps the table at `address` and returns its header. Returns `None` if the
+/// table collides with a kernel mapping.
+unsafe fn map_table(address: usize) -> Option<&'static SdtHeader> {
+    let flags = EntryFlags::NO_EXECUTE;
+    if !memory::identity_map_region(address, mem::size_of::<SdtHeader>(), flags) {
+        println!("ACPI: table {:#x} collides with a kernel mapping", address);
+        return None;
+    }
+    let header = &*(address as *const SdtHeader);
+    if !memory::identity_map_region(address, header.length as usize, flags) {
+        println!("ACPI: table {:#x} collides with a kernel mapping", address);
+        return None;
+    }
+    Some(header)
+}
+
+/// Walks the XSDT, or the RSDT before ACPI 2.0, and parses the known tables.
+/// Tables with an invalid checksum are skipped.
+unsafe fn parse(rsdp: &Rsdp) -> Acpi {
+    let mut acpi = Acpi {
+        revision: rsdp.revision,
+        signatures: Vec::new(),
+        madt: None,
+        fadt: None,
+        s5_sleep_type: None,
+        hpet: None,
+        mcfg: None,
+    };
+
+    let xsdt_address = if rsdp.revision >= 2 {
+        rsdp.extension().xsdt_address as usize
+    } else {
+        0
+    };
+    let (root, entry_size) = if xsdt_address != 0 {
+        (map_table(xsdt_address), 8)
+    } else {
+        (map_table(rsdp.rsdt_address as usize), 4)
+    };
+    let root = match root {
+        Some(root) => root,
+        None => return acpi,
+    };
+    if !root.is_valid() {
+        println!("ACPI: invalid checksum of the root table");
+        return acpi;
+    }
+
+    let entries = root.data();
+    for offset in (0..entries.len() / entry_size).map(|i| i * entry_size) {
+        let address = if entry_size == 8 {
+            read::<u64>(entries, offset) as usize
+        } else {
+            read::<u32>(entries, offset) as usize
+        };
+        let table = match map_table(address) {
+            Some(table) => table,
+            None => continue,
+        };
+        if !table.is_valid() {
+            println!("ACPI: skipping table {:#x} with invalid checksum", address);
+            continue;
+        }
+        acpi.signatures.push(table.signature);
+        match &table.signature {
+            b"APIC" => acpi.madt = Some(Madt::parse(table)),
+            b"FACP" => acpi.fadt = Some(Fadt::parse(table)),
+            b"HPET" => acpi.hpet = Some(Hpet::parse(table)),
+            b"MCFG" => acpi.mcfg = Some(Mcfg::parse(table)),
+            _ => {}
+        }
+    }
+
+    let dsdt_address = acpi.fadt.as_ref().map(|fadt| fadt.dsdt).filter(|&address| address != 0);
+    if let Some(dsdt_address) = dsdt_address {
+        if let Some(dsdt) = map_table(dsdt_address) {
+            if dsdt.is_valid() {
+                acpi.s5_sleep_type = dsdt::find_s5(dsdt);
+            }
+        }
+    }
+
+    acpi
+}
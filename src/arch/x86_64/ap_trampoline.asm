@@ -0,0 +1,106 @@
+; Startup code of the application processors. It is copied to
+; AP_TRAMPOLINE_BASE by `smp::boot_application_processors`, which also fills
+; in the data fields. An AP starts it in real mode after a startup IPI,
+; switches to long mode with the page table of the bootstrap processor and
+; calls the entry function with the argument on the given stack.
+
+global ap_trampoline_start
+global ap_trampoline_end
+
+AP_TRAMPOLINE_BASE equ 0x8000
+
+; address of a trampoline label after the trampoline was copied
+%define RELOCATED(label) (AP_TRAMPOLINE_BASE + label - ap_trampoline_start)
+
+section .rodata.ap_trampoline
+align 16
+bits 16
+ap_trampoline_start:
+        jmp real_mode_start
+
+align 8
+; filled in by the bootstrap processor, see `smp::TrampolineData`
+ap_page_table:
+        dq 0
+ap_stack_top:
+        dq 0
+ap_entry:
+        dq 0
+ap_argument:
+        dq 0
+
+real_mode_start:
+        cli
+        cld
+        xor ax, ax
+        mov ds, ax
+
+        lgdt [RELOCATED(ap_gdt.pointer)]
+
+        ; enable protected mode
+        mov eax, cr0
+        or eax, 1
+        mov cr0, eax
+
+        jmp dword ap_gdt.code32:RELOCATED(protected_mode_start)
+
+bits 32
+protected_mode_start:
+        mov ax, ap_gdt.data
+        mov ds, ax
+        mov es, ax
+        mov ss, ax
+
+        ; enable PAE
+        mov eax, cr4
+        or eax, 1 << 5
+        mov cr4, eax
+
+        ; use the page table of the bootstrap processor
+        mov eax, [RELOCATED(ap_page_table)]
+        mov cr3, eax
+
+        ; set the long mode and no-execute enable bits in the EFER MSR
+        mov ecx, 0xC0000080
+        rdmsr
+        or eax, (1 << 8) | (1 << 11)
+        wrmsr
+
+        ; enable paging and write protection
+        mov eax, cr0
+        or eax, (1 << 31) | (1 << 16)
+        mov cr0, eax
+
+        jmp ap_gdt.code64:RELOCATED(long_mode_start)
+
+bits 64
+long_mode_start:
+        xor ax, ax
+        mov ss, ax
+        mov ds, ax
+        mov es, ax
+        mov fs, ax
+        mov gs, ax
+
+        mov rsp, [rel ap_stack_top]
+        mov rdi, [rel ap_argument]
+        mov rax, [rel ap_entry]
+        call rax
+
+        ; the entry function doesn't return
+        hlt
+
+align 8
+ap_gdt:
+        dq 0
+.code32: equ $ - ap_gdt
+        dq 0x00cf9a000000ffff
+.data: equ $ - ap_gdt
+        dq 0x00cf92000000ffff
+.code64: equ $ - ap_gdt
+        dq (1<<43) | (1<<44) | (1<<47) | (1<<53)
+.pointer:
+        dw $ - ap_gdt - 1
+        dq RELOCATED(ap_gdt)
+
+ap_trampoline_end:
@@ -0,0 +1,38 @@
+global switch_context
+global thread_start
+
+section .text
+bits 64
+
+; Saves the callee-saved registers and the flags of the current thread on
+; its stack, stores its stack pointer to [rdi] and continues the thread whose
+; stack pointer is in rsi. See `thread::switch_context`.
+switch_context:
+        pushfq
+        push rbx
+        push rbp
+        push r12
+        push r13
+        push r14
+        push r15
+        mov [rdi], rsp
+
+        mov rsp, rsi
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbp
+        pop rbx
+        popfq
+        ret
+
+; The first code of a new thread, `switch_context` returns to it. The initial
+; stack of the thread holds the entry function in r13 and its argument in
+; r12.
+thread_start:
+        mov rdi, r12
+        call r13
+
+        ; the entry function doesn't return
+        ud2
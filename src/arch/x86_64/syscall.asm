@@ -0,0 +1,78 @@
+global syscall_entry
+global syscall_interrupt_entry
+extern syscall_handler
+
+section .text
+bits 64
+
+; Pushes the argument registers in the layout of `syscall::Registers`.
+%macro PUSH_REGISTERS 0
+        push r9
+        push r8
+        push r10
+        push rdx
+        push rsi
+        push rdi
+        push rax
+%endmacro
+
+%macro POP_REGISTERS 0
+        pop rax
+        pop rdi
+        pop rsi
+        pop rdx
+        pop r10
+        pop r8
+        pop r9
+%endmacro
+
+; Entry of the `syscall` instruction (LSTAR). The CPU saved the user rip in
+; rcx and the user rflags in r11 and disabled interrupts (SFMASK), but it
+; didn't switch the stack or the GS base. After `swapgs`, the kernel stack
+; comes from the per-CPU block (`gs:8`), `gs:16` is scratch space, see
+; `smp::percpu::PerCpu`.
+syscall_entry:
+        swapgs
+        mov [gs:16], rsp
+        mov rsp, [gs:8]
+        push qword [gs:16]
+        push r11
+        push rcx
+        PUSH_REGISTERS
+
+        mov rdi, rsp
+        call syscall_handler
+
+        ; the handler enables interrupts, they must be off until sysret
+        cli
+        POP_REGISTERS
+        pop rcx
+        pop r11
+        pop rsp
+        swapgs
+        o64 sysret
+
+; Handler of `int 0x80`. The CPU already switched to RSP0 and pushed the
+; interrupt frame, so only rcx and r11 need to be saved additionally. The GS
+; base is swapped if the saved CS (`rsp + 8`) is from ring 3.
+syscall_interrupt_entry:
+        test qword [rsp + 8], 3
+        jz .kernel_gs
+        swapgs
+.kernel_gs:
+        push r11
+        push rcx
+        PUSH_REGISTERS
+
+        mov rdi, rsp
+        call syscall_handler
+
+        cli
+        POP_REGISTERS
+        pop rcx
+        pop r11
+        test qword [rsp + 8], 3
+        jz .return
+        swapgs
+.return:
+        iretq
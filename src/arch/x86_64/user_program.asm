@@ -0,0 +1,35 @@
+; A tiny position independent ring 3 program that proves that user mode and
+; both system call entries work. `usermode::spawn_embedded_program` copies it
+; to a user page. See `syscall` for the numbers and the argument registers.
+
+global user_program_start
+global user_program_end
+
+SYS_EXIT equ 0
+SYS_WRITE equ 1
+
+section .rodata.user_program
+bits 64
+user_program_start:
+        mov rax, SYS_WRITE
+        lea rdi, [rel syscall_message]
+        mov rsi, syscall_message_end - syscall_message
+        syscall
+
+        mov rax, SYS_WRITE
+        lea rdi, [rel interrupt_message]
+        mov rsi, interrupt_message_end - interrupt_message
+        int 0x80
+
+        mov rax, SYS_EXIT
+        xor rdi, rdi
+        syscall
+        ud2
+
+syscall_message:
+        db "hello from ring 3 (syscall)", 10
+syscall_message_end:
+interrupt_message:
+        db "hello from ring 3 (int 0x80)", 10
+interrupt_message_end:
+user_program_end:
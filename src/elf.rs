@@ -0,0 +1,377 @@
+//! Loads statically linked ELF64 executables into an `AddressSpace`.
+
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr;
+use core::slice;
+use memory::{AddressSpace, EntryFlags, PAGE_SIZE};
+use usermode::{USER_SPACE_END, USER_SPACE_START, USER_STACK_PAGES, USER_STACK_TOP};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3e;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+
+/// Types of the auxiliary vector entries passed on the stack
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// Why an executable could not be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The image is shorter than the headers say
+    Truncated,
+    /// The image doesn't start with the ELF magic
+    NotElf,
+    /// Not a little endian x86_64 executable of the current ELF version
+    Unsupported,
+    InvalidProgramHeader,
+    /// A segment isn't completely in user space
+    SegmentOutsideUserSpace,
+    /// Two segments share a page
+    OverlappingSegments,
+    /// A segment shares a page with the user stack below `USER_STACK_TOP`
+    SegmentOverlapsStack,
+    /// The entry point isn't in an executable segment
+    InvalidEntry,
+    /// argv and envp don't fit on the user stack
+    ArgumentsTooLong,
+    OutOfMemory,
+}
+
+/// A program that is ready to be entered, see `usermode::enter`.
+#[derive(Debug, Clone, Copy)]
+pub struct Program {
+    pub entry: usize,
+    /// Points to `argc`, followed by argv, envp and the auxiliary vector
+    pub stack_pointer: usize,
+}
+
+/// The fields of the ELF header we need.
+struct Header {
+    entry: usize,
+    program_header_offset: usize,
+    program_header_count: usize,
+}
+
+/// A program header.
+#[derive(Clone, Copy)]
+struct Segment {
+    kind: u32,
+    flags: u32,
+    offset: usize,
+    address: usize,
+    file_size: usize,
+    memory_size: usize,
+}
+
+/// Maps the `PT_LOAD` segments of the executable `image` into
+/// `address_space`, zero-fills the rest of each segment (e.g. `.bss`) and
+/// sets up the user stack with `argv`, `envp` and the auxiliary vector.
+/// Mappings that were made before an error are freed with the address space.
+pub fn load(
+    address_space: &mut AddressSpace,
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<Program, Error> {
+    let header = parse_header(image)?;
+    let segments = (0..header.program_header_count)
+        .map(|index| parse_segment(image, header.program_header_offset + index * PROGRAM_HEADER_SIZE))
+        .filter(|segment| segment.kind == PT_LOAD);
+
+    let mut entry_is_executable = false;
+    let mut program_headers_address = None;
+    for segment in segments {
+        validate_segment(image, &segment)?;
+        let start = segment.address & !(PAGE_SIZE - 1);
+        let end = segment.address + segment.memory_size;
+        if segment.memory_size == 0 {
+            continue;
+        }
+
+        if !address_space.is_unmapped(start, end - start) {
+            return Err(Error::OverlappingSegments);
+        }
+        let flags = EntryFlags::from_elf_program_flags(segment.flags);
+        if !address_space.map_region(start, end - start, flags) {
+            return Err(Error::OutOfMemory);
+        }
+        // the rest up to `memory_size` stays zeroed
+        let file_end = segment.offset + segment.file_size;
+        if !address_space.copy_to(segment.address, &image[segment.offset..file_end]) {
+            return Err(Error::OutOfMemory);
+        }
+
+        if segment.address <= header.entry && header.entry < end {
+            entry_is_executable = !flags.contains(EntryFlags::NO_EXECUTE);
+        }
+        if segment.offset <= header.program_header_offset && header.program_header_offset < file_end {
+            program_headers_address = Some(segment.address + header.program_header_offset - segment.offset);
+        }
+    }
+    if !entry_is_executable {
+        return Err(Error::InvalidEntry);
+    }
+
+    let mut auxiliary_vector = Vec::new();
+    auxiliary_vector.push((AT_PHENT, PROGRAM_HEADER_SIZE as u64));
+    auxiliary_vector.push((AT_PHNUM, header.program_header_count as u64));
+    auxiliary_vector.push((AT_PAGESZ, PAGE_SIZE as u64));
+    auxiliary_vector.push((AT_ENTRY, header.entry as u64));
+    if let Some(address) = program_headers_address {
+        auxiliary_vector.push((AT_PHDR, address as u64));
+    }
+    let stack_pointer = setup_stack(address_space, argv, envp, &auxiliary_vector)?;
+
+    Ok(Program {
+        entry: header.entry,
+        stack_pointer: stack_pointer,
+    })
+}
+
+fn parse_header(image: &[u8]) -> Result<Header, Error> {
+    if image.len() < HEADER_SIZE {
+        return Err(Error::Truncated);
+    }
+    if image[0..4] != ELF_MAGIC {
+        return Err(Error::NotElf);
+    }
+    if image[4] != CLASS_64
+        || image[5] != DATA_LITTLE_ENDIAN
+        || image[6] != VERSION_CURRENT
+        || read::<u16>(image, 16) != TYPE_EXECUTABLE
+        || read::<u16>(image, 18) != MACHINE_X86_64
+    {
+        return Err(Error::Unsupported);
+    }
+    if read::<u16>(image, 54) as usize != PROGRAM_HEADER_SIZE {
+        return Err(Error::InvalidProgramHeader);
+    }
+
+    let header = Header {
+        entry: read::<u64>(image, 24) as usize,
+        program_header_offset: read::<u64>(image, 32) as usize,
+        program_header_count: read::<u16>(image, 56) as usize,
+    };
+    let table_end = (header.program_header_count * PROGRAM_HEADER_SIZE)
+        .checked_add(header.program_header_offset)
+        .ok_or(Error::Truncated)?;
+    if table_end > image.len() {
+        return Err(Error::Truncated);
+    }
+    Ok(header)
+}
+
+/// Reads the program header at `offset`, which `parse_header` checked.
+fn parse_segment(image: &[u8], offset: usize) -> Segment {
+    Segment {
+        kind: read(image, offset),
+        flags: read(image, offset + 4),
+        offset: read::<u64>(image, offset + 8) as usize,
+        address: read::<u64>(image, offset + 16) as usize,
+        file_size: read::<u64>(image, offset + 32) as usize,
+        memory_size: read::<u64>(image, offset + 40) as usize,
+    }
+}
+
+fn validate_segment(image: &[u8], segment: &Segment) -> Result<(), Error> {
+    if segment.file_size > segment.memory_size {
+        return Err(Error::InvalidProgramHeader);
+    }
+    match segment.offset.checked_add(segment.file_size) {
+        Some(end) if end <= image.len() => {}
+        _ => return Err(Error::Truncated),
+    }
+    let end = match segment.address.checked_add(segment.memory_size) {
+        Some(end) if segment.address >= USER_SPACE_START && end <= USER_SPACE_END => end,
+        _ => return Err(Error::SegmentOutsideUserSpace),
+    };
+    // `setup_stack` maps the stack after the segments
+    let stack_bottom = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+    if segment.memory_size > 0 && segment.address < USER_STACK_TOP && end > stack_bottom {
+        return Err(Error::SegmentOverlapsStack);
+    }
+    Ok(())
+}
+
+/// Maps the user stack below `USER_STACK_TOP` and fills it as the System V
+/// ABI describes it: `argc`, the argv and envp pointers (each terminated by
+/// a null pointer), the auxiliary vector (terminated by `AT_NULL`) and the
+/// strings at the top. Returns the 16 byte aligned stack pointer.
+fn setup_stack(
+    address_space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    auxiliary_vector: &[(u64, u64)],
+) -> Result<usize, Error> {
+    let stack_size = USER_STACK_PAGES * PAGE_SIZE;
+    let stack_bottom = USER_STACK_TOP - stack_size;
+
+    // the strings, null terminated, and their offsets
+    let mut strings = Vec::new();
+    let mut offsets = Vec::new();
+    for string in argv.iter().chain(envp.iter()) {
+        offsets.push(strings.len());
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+    let strings_address = USER_STACK_TOP - strings.len();
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    let (argv_offsets, envp_offsets) = offsets.split_at(argv.len());
+    for &offset in argv_offsets {
+        words.push((strings_address + offset) as u64);
+    }
+    words.push(0);
+    for &offset in envp_offsets {
+        words.push((strings_address + offset) as u64);
+    }
+    words.push(0);
+    for &(kind, value) in auxiliary_vector {
+        words.push(kind);
+        words.push(value);
+    }
+    words.push(AT_NULL);
+    words.push(0);
+
+    let words_size = words.len() * mem::size_of::<u64>();
+    let stack_pointer = match strings_address.checked_sub(words_size) {
+        Some(address) => address & !0xf,
+        None => return Err(Error::ArgumentsTooLong),
+    };
+    if stack_pointer < stack_bottom + PAGE_SIZE {
+        // keep at least a page for the program
+        return Err(Error::ArgumentsTooLong);
+    }
+
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    if !address_space.map_region(stack_bottom, stack_size, flags) {
+        return Err(Error::OutOfMemory);
+    }
+    let words = unsafe { slice::from_raw_parts(words.as_ptr() as *const u8, words_size) };
+    if !address_space.copy_to(strings_address, &strings) || !address_space.copy_to(stack_pointer, words) {
+        return Err(Error::OutOfMemory);
+    }
+    Ok(stack_pointer)
+}
+
+/// Reads a `T` from the possibly unaligned `offset` in `bytes`.
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> T {
+    let bytes = &bytes[offset..offset + mem::size_of::<T>()];
+    unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+
+    const ENTRY: usize = USER_SPACE_START + 0x10;
+
+    /// Builds an executable with one segment of `code` at `USER_SPACE_START`
+    /// that is `memory_size` bytes large in memory.
+    fn build_image(code: &[u8], memory_size: usize) -> Vec<u8> {
+        let mut image = vec_of_zeros(HEADER_SIZE + PROGRAM_HEADER_SIZE);
+        image[0..4].copy_from_slice(&ELF_MAGIC);
+        image[4] = CLASS_64;
+        image[5] = DATA_LITTLE_ENDIAN;
+        image[6] = VERSION_CURRENT;
+        write(&mut image, 16, TYPE_EXECUTABLE);
+        write(&mut image, 18, MACHINE_X86_64);
+        write(&mut image, 24, ENTRY as u64);
+        write(&mut image, 32, HEADER_SIZE as u64);
+        write(&mut image, 54, PROGRAM_HEADER_SIZE as u16);
+        write(&mut image, 56, 1u16);
+
+        let segment = HEADER_SIZE;
+        write(&mut image, segment, PT_LOAD);
+        write(&mut image, segment + 4, 0b101u32); // readable and executable
+        write(&mut image, segment + 8, image.len() as u64);
+        write(&mut image, segment + 16, USER_SPACE_START as u64);
+        write(&mut image, segment + 32, code.len() as u64);
+        write(&mut image, segment + 40, memory_size as u64);
+        image.extend_from_slice(code);
+        image
+    }
+
+    fn vec_of_zeros(size: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.resize(size, 0);
+        bytes
+    }
+
+    fn write<T: Copy>(bytes: &mut [u8], offset: usize, value: T) {
+        let bytes = &mut bytes[offset..offset + mem::size_of::<T>()];
+        unsafe { ptr::write_unaligned(bytes.as_mut_ptr() as *mut T, value) }
+    }
+
+    kernel_test!(elf_load_maps_segments_and_stack {
+        let image = build_image(&[0x90; 0x20], 3 * PAGE_SIZE);
+        let mut space = AddressSpace::new().expect("no frames available");
+        let program = load(&mut space, &image, &["init", "-v"], &["HOME=/"])
+            .expect("could not load the image");
+
+        assert_eq!(program.entry, ENTRY);
+        assert_eq!(program.stack_pointer % 16, 0);
+        assert!(program.stack_pointer < USER_STACK_TOP);
+        // the zero-filled part of the segment is mapped as well
+        assert!(space.translate(USER_SPACE_START + 2 * PAGE_SIZE).is_some());
+        assert!(space.translate(USER_SPACE_START + 3 * PAGE_SIZE).is_none());
+    });
+
+    kernel_test!(elf_load_passes_the_environment_without_arguments {
+        let image = build_image(&[0x90; 0x20], 0x20);
+        let mut space = AddressSpace::new().expect("no frames available");
+        let program = load(&mut space, &image, &[], &["A=B"]).expect("could not load the image");
+
+        // argc, the argv null pointer, one envp pointer and its null pointer
+        let mut words = [0u64; 4];
+        {
+            let bytes = unsafe { slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, 32) };
+            assert!(space.copy_from(program.stack_pointer, bytes));
+        }
+        assert_eq!(words[0], 0);
+        assert_eq!(words[1], 0);
+        assert!(words[2] != 0);
+        assert_eq!(words[3], 0);
+
+        let mut variable = [0u8; 4];
+        assert!(space.copy_from(words[2] as usize, &mut variable));
+        assert_eq!(&variable, b"A=B\0");
+    });
+
+    kernel_test!(elf_load_rejects_invalid_images {
+        let mut space = AddressSpace::new().expect("no frames available");
+
+        let mut image = build_image(&[0x90; 0x20], 0x20);
+        image[0] = 0;
+        assert_eq!(load(&mut space, &image, &[], &[]).err(), Some(Error::NotElf));
+
+        let image = build_image(&[0x90; 0x20], 0x10);
+        assert_eq!(
+            load(&mut space, &image, &[], &[]).err(),
+            Some(Error::InvalidProgramHeader)
+        );
+
+        let image = build_image(&[0x90; 0x20], 0x20);
+        assert_eq!(load(&mut space, &image[..40], &[], &[]).err(), Some(Error::Truncated));
+
+        let mut image = build_image(&[0x90; 0x20], 0x20);
+        write(&mut image, HEADER_SIZE + 16, (USER_STACK_TOP - PAGE_SIZE) as u64);
+        assert_eq!(
+            load(&mut space, &image, &[], &[]).err(),
+            Some(Error::SegmentOverlapsStack)
+        );
+    });
+}
@@ -0,0 +1,289 @@
+use core::mem;
+use gdt;
+use memory;
+use smp::percpu;
+use spin::Mutex;
+use thread;
+use x86_64;
+use x86_64::structures::idt::{ExceptionStackFrame, InterruptDescriptorTable, PageFaultErrorCode};
+
+/// Prints to both the VGA text buffer and the serial interface.
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        println!($($arg)*);
+        serial_println!($($arg)*);
+    }};
+}
+
+/// The architectural CPU exceptions, numbered by their interrupt vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    SecurityException = 30,
+}
+
+impl Exception {
+    /// Traps report the instruction after the one that caused them, so
+    /// execution can simply continue.
+    fn is_trap(&self) -> bool {
+        match *self {
+            Exception::Debug | Exception::Breakpoint | Exception::Overflow => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the error code of the exception is a segment
+    /// selector error code.
+    fn has_selector_error_code(&self) -> bool {
+        match *self {
+            Exception::InvalidTss
+            | Exception::SegmentNotPresent
+            | Exception::StackSegmentFault
+            | Exception::GeneralProtectionFault => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the handler runs on an interrupt stack of the TSS,
+    /// which the next exception of the same kind reuses.
+    fn uses_interrupt_stack(&self) -> bool {
+        match *self {
+            Exception::NonMaskableInterrupt
+            | Exception::DoubleFault
+            | Exception::MachineCheck => true,
+            _ => false,
+        }
+    }
+}
+
+/// Everything that is known about an exception when it is dispatched.
+pub struct ExceptionInfo<'a> {
+    pub exception: Exception,
+    pub stack_frame: &'a mut ExceptionStackFrame,
+    /// The error code pushed by the CPU, if the exception has one
+    pub error_code: Option<u64>,
+    /// The accessed address of a page fault (CR2)
+    pub fault_address: Option<usize>,
+}
+
+/// A function that tries to recover from an exception. Returns `true` if it
+/// did, in which case execution continues at the address in the stack frame.
+pub type ExceptionHook = fn(&mut ExceptionInfo) -> bool;
+
+static HOOKS: Mutex<[Option<ExceptionHook>; 32]> = Mutex::new([None; 32]);
+
+/// Registers `hook` to be called before the default handling of `exception`.
+/// Returns the previously registered hook.
+pub fn register_hook(exception: Exception, hook: ExceptionHook) -> Option<ExceptionHook> {
+    mem::replace(&mut HOOKS.lock()[exception as usize], Some(hook))
+}
+
+/// Removes the hook of `exception` and returns it.
+pub fn unregister_hook(exception: Exception) -> Option<ExceptionHook> {
+    HOOKS.lock()[exception as usize].take()
+}
+
+/// Installs the handlers of all exceptions in the given IDT.
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.divide_by_zero.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    // these can hit at any time, so they get stacks that are known to be good
+    unsafe {
+        idt.non_maskable_interrupt
+            .set_handler_fn(non_maskable_interrupt_handler)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.security_exception.set_handler_fn(security_exception_handler);
+}
+
+/// Runs the hook of the exception and falls back to reporting it. Returns
+/// only if the exception was recovered or is a trap. Other faults of user
+/// programs end the faulting thread, other faults of the kernel halt it.
+fn dispatch(info: &mut ExceptionInfo) {
+    let _gs = percpu::KernelGs::enter();
+    // copy the hook out, so that it can (un)register hooks itself
+    let hook = HOOKS.lock()[info.exception as usize];
+    if let Some(hook) = hook {
+        if hook(info) {
+            return;
+        }
+    }
+
+    print_report(info);
+    if !info.exception.is_trap() {
+        // a fault of a user program only ends its thread, `_gs` isn't needed
+        // since the thread never returns to ring 3
+        let from_user = info.stack_frame.code_segment & 3 == 3;
+        if from_user && !info.exception.uses_interrupt_stack() {
+            report!("thread {:?} ended", thread::current());
+            thread::exit();
+        }
+        report!("kernel halted");
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+/// Prints a report of the exception.
+fn print_report(info: &ExceptionInfo) {
+    report!("EXCEPTION: {:?}", info.exception);
+    if let Some(error_code) = info.error_code {
+        report!("  error code: {:#x}", error_code);
+        if info.exception.has_selector_error_code() && error_code != 0 {
+            report_selector_error_code(error_code);
+        }
+        if info.exception == Exception::PageFault {
+            report_page_fault(
+                PageFaultErrorCode::from_bits_truncate(error_code),
+                info.fault_address.unwrap(),
+            );
+        }
+    }
+    report!("{:#?}", info.stack_frame);
+}
+
+/// Decodes the selector error code of #TS, #NP, #SS and #GP.
+fn report_selector_error_code(error_code: u64) {
+    let table = match (error_code >> 1) & 0b11 {
+        0b00 => "GDT",
+        0b10 => "LDT",
+        _ => "IDT",
+    };
+    let index = (error_code >> 3) & 0x1fff;
+    let external = if error_code & 1 != 0 { " (external event)" } else { "" };
+    report!("  selector: {} index {}{}", table, index, external);
+}
+
+/// Decodes the page fault error code, prints the page table entries of the
+/// accessed address and checks for guard page hits.
+fn report_page_fault(error_code: PageFaultErrorCode, address: usize) {
+    let cause = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+    let mode = if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        "user"
+    } else {
+        "kernel"
+    };
+
+    report!("  accessed address: {:#x}", address);
+    report!("  {} on {} in {} mode", cause, access, mode);
+    if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        report!("  reserved bit set in a page table entry");
+    }
+    memory::print_page_walk(address);
+    if let Some(description) = memory::guard_page_containing(address) {
+        report!("  hit the guard page of {}", description);
+    }
+}
+
+/// Defines a handler for an exception without error code.
+macro_rules! handler {
+    ($name:ident, $exception:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame) {
+            dispatch(&mut ExceptionInfo {
+                exception: $exception,
+                stack_frame: stack_frame,
+                error_code: None,
+                fault_address: None,
+            });
+        }
+    };
+}
+
+/// Defines a handler for an exception with error code.
+macro_rules! handler_with_error_code {
+    ($name:ident, $exception:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+            dispatch(&mut ExceptionInfo {
+                exception: $exception,
+                stack_frame: stack_frame,
+                error_code: Some(error_code),
+                fault_address: None,
+            });
+        }
+    };
+}
+
+handler!(divide_error_handler, Exception::DivideError);
+handler!(debug_handler, Exception::Debug);
+handler!(non_maskable_interrupt_handler, Exception::NonMaskableInterrupt);
+handler!(breakpoint_handler, Exception::Breakpoint);
+handler!(overflow_handler, Exception::Overflow);
+handler!(bound_range_exceeded_handler, Exception::BoundRangeExceeded);
+handler!(invalid_opcode_handler, Exception::InvalidOpcode);
+handler!(device_not_available_handler, Exception::DeviceNotAvailable);
+handler_with_error_code!(double_fault_handler, Exception::DoubleFault);
+handler_with_error_code!(invalid_tss_handler, Exception::InvalidTss);
+handler_with_error_code!(segment_not_present_handler, Exception::SegmentNotPresent);
+handler_with_error_code!(stack_segment_fault_handler, Exception::StackSegmentFault);
+handler_with_error_code!(general_protection_fault_handler, Exception::GeneralProtectionFault);
+handler!(x87_floating_point_handler, Exception::X87FloatingPoint);
+handler_with_error_code!(alignment_check_handler, Exception::AlignmentCheck);
+handler!(machine_check_handler, Exception::MachineCheck);
+handler!(simd_floating_point_handler, Exception::SimdFloatingPoint);
+handler!(virtualization_handler, Exception::Virtualization);
+handler_with_error_code!(security_exception_handler, Exception::SecurityException);
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: &mut ExceptionStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    dispatch(&mut ExceptionInfo {
+        exception: Exception::PageFault,
+        stack_frame: stack_frame,
+        error_code: Some(error_code.bits()),
+        fault_address: Some(Cr2::read().as_u64() as usize),
+    });
+}
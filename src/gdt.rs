@@ -1,49 +1,197 @@
+use alloc::boxed::Box;
+use memory;
+use smp::percpu;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 use x86_64::structures::tss::TaskStateSegment;
+use x86_64::PrivilegeLevel;
 use x86_64::VirtAddr;
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// Segment descriptors that the x86_64 crate has no constructor for:
+/// present, writable data segments for ring 0 and ring 3
+const KERNEL_DATA_SEGMENT: u64 = 0x00cf_9200_0000_ffff;
+const USER_DATA_SEGMENT: u64 = 0x00cf_f200_0000_ffff;
+
+/// The selectors of the GDT built by `CpuTablesBuilder`. The segments are
+/// ordered as `SYSCALL`/`SYSRET` expect them: kernel code, kernel data, user
+/// data and user code.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Size of the interrupt stacks in pages
+const DOUBLE_FAULT_STACK_PAGES: usize = 1;
+const NMI_STACK_PAGES: usize = 1;
+const MACHINE_CHECK_STACK_PAGES: usize = 1;
+/// Size of the stack used for interrupts in ring 3 until a thread sets its
+/// own, in pages
+const PRIVILEGE_STACK_PAGES: usize = 4;
+
+/// Size of each interrupt stack of the boot GDT
+const BOOT_STACK_SIZE: usize = 4096;
+
+/// The interrupt stacks of the boot TSS, one per IST index
+static mut BOOT_STACKS: [[u8; BOOT_STACK_SIZE]; 3] = [[0; BOOT_STACK_SIZE]; 3];
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+    /// A TSS with static interrupt stacks, used until `init` can allocate
+    /// stacks with guard pages.
+    static ref BOOT_TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        let indexes = [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX];
+        for (stack, &index) in unsafe { BOOT_STACKS.iter() }.zip(indexes.iter()) {
+            let stack_start = VirtAddr::from_ptr(stack);
+            tss.interrupt_stack_table[index as usize] = stack_start + BOOT_STACK_SIZE;
+        }
+        tss
+    };
+    static ref BOOT_GDT: (GlobalDescriptorTable, SegmentSelector, SegmentSelector) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&BOOT_TSS));
+        (gdt, code_selector, tss_selector)
     };
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+}
+
+/// The GDT and the TSS of a CPU. They are never freed, because the CPU uses
+/// them until it is reset.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    /// Also referenced by the TSS descriptor in the GDT
+    tss: *mut TaskStateSegment,
+    pub selectors: Selectors,
+}
+
+impl CpuTables {
+    /// Sets the stack that the CPU switches to when an interrupt arrives in
+    /// ring 3 (`privilege_stack_table[0]`, RSP0).
+    pub fn set_kernel_stack(&self, stack_top: usize) {
+        unsafe {
+            (*self.tss).privilege_stack_table[0] = VirtAddr::new(stack_top as u64);
+        }
+    }
+
+    /// Returns the stack set by `set_kernel_stack`.
+    pub fn kernel_stack(&self) -> usize {
+        unsafe { (*self.tss).privilege_stack_table[0].as_u64() as usize }
+    }
+}
+
+/// Builds the GDT and the TSS of a CPU. The stacks are allocated through the
+/// paging subsystem, so they have guard pages.
+pub struct CpuTablesBuilder {
+    tss: TaskStateSegment,
+}
+
+impl CpuTablesBuilder {
+    pub fn new() -> CpuTablesBuilder {
+        CpuTablesBuilder {
+            tss: TaskStateSegment::new(),
+        }
+    }
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
+    /// Allocates a stack of `pages` pages for the interrupt stack table entry
+    /// `index`, see `set_stack_index` of the IDT entries.
+    pub fn interrupt_stack(mut self, index: u16, pages: usize) -> CpuTablesBuilder {
+        self.tss.interrupt_stack_table[index as usize] = allocate_stack(pages);
+        self
+    }
+
+    /// Allocates a stack of `pages` pages for interrupts in ring 3, see
+    /// `CpuTables::set_kernel_stack`.
+    pub fn privilege_stack(mut self, pages: usize) -> CpuTablesBuilder {
+        self.tss.privilege_stack_table[0] = allocate_stack(pages);
+        self
+    }
+
+    /// Creates the GDT and loads both tables on the current CPU.
+    pub fn load(self) -> &'static CpuTables {
+        use x86_64::instructions::segmentation::set_cs;
+        use x86_64::instructions::tables::load_tss;
+
+        let tss = Box::into_raw(Box::new(self.tss));
+        let mut gdt = GlobalDescriptorTable::new();
+        let selectors = Selectors {
+            kernel_code: gdt.add_entry(Descriptor::kernel_code_segment()),
+            kernel_data: gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA_SEGMENT)),
+            user_data: gdt.add_entry(Descriptor::UserSegment(USER_DATA_SEGMENT)),
+            user_code: gdt.add_entry(Descriptor::user_code_segment()),
+            tss: gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss })),
         };
-        tss
-    };
+        let selectors = Selectors {
+            user_data: SegmentSelector::new(selectors.user_data.index(), PrivilegeLevel::Ring3),
+            user_code: SegmentSelector::new(selectors.user_code.index(), PrivilegeLevel::Ring3),
+            ..selectors
+        };
+        let tables: &'static CpuTables = Box::leak(Box::new(CpuTables {
+            gdt: gdt,
+            tss: tss,
+            selectors: selectors,
+        }));
+
+        tables.gdt.load();
+        unsafe {
+            set_cs(selectors.kernel_code);
+            asm!("mov ss, $0
+                  mov ds, $0
+                  mov es, $0"
+                 :: "r"(selectors.kernel_data.0) : "memory" : "intel", "volatile");
+            load_tss(selectors.tss);
+        }
+        tables
+    }
+}
+
+fn allocate_stack(pages: usize) -> VirtAddr {
+    let stack = memory::alloc_stack(pages).expect("could not allocate interrupt stack");
+    VirtAddr::new(stack.top() as u64)
 }
 
-pub fn init() {
+/// Loads a GDT and TSS that need neither the heap nor the paging subsystem,
+/// so that the IST entries of the IDT are valid before interrupts are
+/// enabled. `init` replaces them once memory is set up.
+pub fn init_boot() {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    BOOT_GDT.0.load();
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        set_cs(BOOT_GDT.1);
+        load_tss(BOOT_GDT.2);
     }
 }
+
+/// Creates and loads the GDT and the TSS of the current CPU. Every CPU needs
+/// its own TSS, because the TSS holds the interrupt stacks. Needs
+/// `memory::init` to be called before, because the stacks are allocated
+/// through the paging subsystem, and the heap, which holds the tables.
+pub fn init() -> &'static CpuTables {
+    CpuTablesBuilder::new()
+        .interrupt_stack(DOUBLE_FAULT_IST_INDEX, DOUBLE_FAULT_STACK_PAGES)
+        .interrupt_stack(NMI_IST_INDEX, NMI_STACK_PAGES)
+        .interrupt_stack(MACHINE_CHECK_IST_INDEX, MACHINE_CHECK_STACK_PAGES)
+        .privilege_stack(PRIVILEGE_STACK_PAGES)
+        .load()
+}
+
+/// Sets the ring 0 stack of the current CPU for interrupts and system calls
+/// in ring 3, see `CpuTables::set_kernel_stack`. Called on context switch.
+pub fn set_kernel_stack(stack_top: usize) {
+    let cpu = percpu::current();
+    cpu.tables.set_kernel_stack(stack_top);
+    cpu.set_syscall_stack(stack_top);
+}
+
+/// Returns the selectors of the GDT of the current CPU.
+pub fn selectors() -> Selectors {
+    percpu::current().tables.selectors
+}
@@ -0,0 +1,62 @@
+//! The initial ramdisk: files that GRUB loads next to the kernel with
+//! `module2` entries. The command line of an entry is used as the name of
+//! its file.
+
+use alloc::vec::Vec;
+use core::slice;
+use memory::{self, EntryFlags};
+use spin::Once;
+
+/// A file of the initial ramdisk.
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    pub name: &'static str,
+    pub bytes: &'static [u8],
+}
+
+static MODULES: Once<Vec<Module>> = Once::new();
+
+/// Maps the modules into the kernel address space. Their frames were
+/// reserved by `memory::init`. Must be called after the heap is initialized.
+pub fn init() -> &'static [Module] {
+    let modules = MODULES.call_once(|| {
+        let boot_info = memory::boot_info();
+        let mut modules = Vec::new();
+        for module in boot_info.module_tags() {
+            let name = module_name(module.name());
+            let start = module.start_address() as usize;
+            let size = (module.end_address() as usize).saturating_sub(start);
+            // the kernel expects to reach the modules at their physical
+            // address, so another mapping there is fatal
+            if size > 0 && !memory::identity_map_region(start, size, EntryFlags::NO_EXECUTE) {
+                panic!("module {:?} at {:#x} collides with a kernel mapping", name, start);
+            }
+            modules.push(Module {
+                name: name,
+                bytes: unsafe { slice::from_raw_parts(start as *const u8, size) },
+            });
+        }
+        modules
+    });
+    &modules[..]
+}
+
+/// Returns the command line of a module tag without the null terminator,
+/// which `ModuleTag::name` may include. The multiboot information stays
+/// identity mapped, so the name lives as long as the kernel.
+pub fn module_name<'a>(name: &'a str) -> &'a str {
+    name.trim_right_matches('\0')
+}
+
+/// Returns the modules mapped by `init`, in the order of `grub.cfg`.
+pub fn modules() -> &'static [Module] {
+    MODULES.try().map(|modules| &modules[..]).unwrap_or(&[])
+}
+
+/// Returns the contents of the module called `name`.
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    modules()
+        .iter()
+        .find(|module| module.name == name)
+        .map(|module| module.bytes)
+}
@@ -0,0 +1,41 @@
+use memory::{self, EntryFlags, PAGE_SIZE};
+
+/// Checks that every page of every loaded ELF section was mapped by
+/// `remap_the_kernel` with the flags derived from the section flags.
+pub fn run() -> ! {
+    serial_print!("kernel sections are mapped with their ELF flags... ");
+    let checked_flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+
+    let boot_info = memory::boot_info();
+    let elf_sections_tag = boot_info
+        .elf_sections_tag()
+        .expect("Elf-sections tag required");
+    for section in elf_sections_tag.sections().filter(|s| s.is_allocated()) {
+        let expected = EntryFlags::from_elf_section_flags(&section) & checked_flags;
+        let start = section.start_address() as usize;
+        let end = section.end_address() as usize;
+        for address in (start..end).step_by(PAGE_SIZE) {
+            if memory::guard_page_containing(address).is_some() {
+                // the old P4 table in .bss was unmapped on purpose
+                continue;
+            }
+            match memory::page_flags(address) {
+                Some(flags) if flags & checked_flags == expected => {}
+                Some(flags) => {
+                    serial_println!(
+                        "page {:#x}: expected {:?}, found {:?}",
+                        address,
+                        expected,
+                        flags & checked_flags
+                    );
+                    super::fail("wrong flags");
+                }
+                None => {
+                    serial_println!("page {:#x} is not mapped", address);
+                    super::fail("section not mapped");
+                }
+            }
+        }
+    }
+    super::pass();
+}
@@ -0,0 +1,29 @@
+use core::ptr;
+use exceptions::{self, Exception, ExceptionInfo};
+use memory;
+
+/// Writes to the guard page that `remap_the_kernel` leaves below the boot
+/// stack in place of the boot P4 table, which must page fault.
+pub fn run() -> ! {
+    serial_print!("guard page below the boot stack faults... ");
+    exceptions::register_hook(Exception::PageFault, page_fault_hook);
+
+    let guard_page = memory::boot_stack_guard_page();
+    if memory::translate(guard_page).is_some() {
+        super::fail("the guard page is mapped");
+    }
+    unsafe {
+        ptr::write_volatile((guard_page + memory::PAGE_SIZE - 1) as *mut u8, 42);
+    }
+    super::fail("write to the guard page did not fault");
+}
+
+fn page_fault_hook(info: &mut ExceptionInfo) -> bool {
+    if info.fault_address == Some(memory::boot_stack_guard_page() + memory::PAGE_SIZE - 1) {
+        super::pass();
+    }
+    match memory::guard_page_containing(info.fault_address.unwrap()) {
+        Some(description) => super::fail(description),
+        None => super::fail("page fault outside of a guard page"),
+    }
+}
@@ -0,0 +1,29 @@
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::mem;
+
+/// Size of each allocation in bytes
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Allocates blocks until the heap can't grow anymore. Running out of
+/// physical memory must end in the `oom` handler instead of a crash.
+pub fn run() -> ! {
+    serial_print!("heap exhaustion reaches the oom handler... ");
+    let mut blocks = Vec::new();
+    loop {
+        let block: Vec<u8> = Vec::with_capacity(BLOCK_SIZE);
+        blocks.push(block);
+        if blocks.len() > 64 * 1024 {
+            mem::forget(blocks);
+            super::fail("heap did not run out of memory");
+        }
+    }
+}
+
+/// Called by the `oom` handler.
+pub fn out_of_memory(layout: Layout) -> ! {
+    if layout.size() < BLOCK_SIZE {
+        super::fail("small allocation failed before the heap was exhausted");
+    }
+    super::pass();
+}
@@ -0,0 +1,43 @@
+//! Boot-level test scenarios. Each scenario is built into its own kernel
+//! image with the cargo feature `itest-<scenario>` (see the `Makefile`),
+//! reports over the serial port and exits QEMU with the result.
+
+use {exit_qemu, QemuExitCode};
+
+#[cfg(feature = "itest-elf-flags")]
+mod elf_flags;
+#[cfg(feature = "itest-guard-page")]
+mod guard_page;
+#[cfg(feature = "itest-heap-oom")]
+pub mod heap_oom;
+#[cfg(feature = "itest-stack-overflow")]
+mod stack_overflow;
+
+#[cfg(feature = "itest-elf-flags")]
+pub use self::elf_flags::run;
+#[cfg(feature = "itest-guard-page")]
+pub use self::guard_page::run;
+#[cfg(feature = "itest-heap-oom")]
+pub use self::heap_oom::run;
+#[cfg(feature = "itest-stack-overflow")]
+pub use self::stack_overflow::run;
+
+/// Reports success and exits QEMU.
+pub fn pass() -> ! {
+    serial_println!("[ok]");
+    unsafe {
+        exit_qemu(QemuExitCode::Success);
+    }
+    loop {}
+}
+
+/// Reports the failure reason and exits QEMU.
+#[allow(dead_code)]
+pub fn fail(reason: &str) -> ! {
+    serial_println!("[failed]");
+    serial_println!("{}", reason);
+    unsafe {
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,26 @@
+use core::ptr;
+use exceptions::{self, Exception, ExceptionInfo};
+
+/// Overflows the kernel stack. The write to the guard page causes a page
+/// fault, which can't be delivered on the overflowed stack and escalates to a
+/// double fault. Reaching the double fault handler at all means that it ran
+/// on its own IST stack, otherwise the CPU would have triple faulted.
+pub fn run() -> ! {
+    serial_print!("stack overflow reaches the double fault handler... ");
+    exceptions::register_hook(Exception::DoubleFault, double_fault_hook);
+
+    stack_overflow();
+    super::fail("stack overflow did not fault");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    let value = 0u64;
+    stack_overflow();
+    // prevent tail call optimization
+    unsafe { ptr::read_volatile(&value) };
+}
+
+fn double_fault_hook(_info: &mut ExceptionInfo) -> bool {
+    super::pass();
+}
@@ -1,12 +0,0 @@
-use pic8259_simple::ChainedPics;
-use spin;
-
-pub const PIC_1_OFFSET: u8 = 32;
-pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
-
-// Hardware Timer uses line 0 of master PIC
-pub const TIMER_INTERRUPT_ID: u8 = PIC_1_OFFSET;
-pub const KEYBOARD_INTERRUPT_ID: u8 = TIMER_INTERRUPT_ID + 1;
@@ -0,0 +1,255 @@
+use acpi;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use interrupts::SPURIOUS_INTERRUPT_ID;
+use memory::{self, EntryFlags};
+use spin::Mutex;
+
+// registers of the local APIC, as offsets from its base address
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+/// Spurious interrupt register: the APIC is software enabled
+const LAPIC_ENABLE: u32 = 1 << 8;
+/// Local vector table: the interrupt is masked
+const LVT_MASKED: u32 = 1 << 16;
+/// Local vector table: the timer restarts when it reaches zero
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Interrupt command: INIT delivery mode, level assert
+const ICR_INIT: u32 = 0b101 << 8 | 1 << 14;
+/// Interrupt command: startup (SIPI) delivery mode
+const ICR_STARTUP: u32 = 0b110 << 8;
+/// Interrupt command: the last interrupt is not yet accepted by the target
+const ICR_SEND_PENDING: u32 = 1 << 12;
+/// Divide configuration: the timer counts at a 16th of the bus frequency
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// registers of an I/O APIC, as indexes for the `IOREGSEL` register
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+/// Redirection entry: the input pin is active low
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+/// Redirection entry: the input pin is level triggered
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+/// Redirection entry: the interrupt is masked
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+const MAX_IO_APICS: usize = 8;
+
+/// Size of the register area of both APIC types
+const MMIO_SIZE: usize = 4096;
+
+/// Virtual (identity mapped) address of the local APIC registers, 0 until
+/// `init` ran. Every CPU sees its own local APIC at the same address.
+static LOCAL_APIC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([None; MAX_IO_APICS]);
+
+/// Returns `true` if the CPU has a local APIC (CPUID `apic` flag).
+pub fn is_supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    let result = unsafe { __cpuid(1) };
+    result.edx & (1 << 9) != 0
+}
+
+/// Maps and enables the local APIC of the current CPU and the I/O APICs
+/// described by the MADT. All I/O APIC inputs are masked afterwards. Returns
+/// `false` if there are no APICs to use.
+pub fn init() -> bool {
+    let madt = match acpi::tables().and_then(|acpi| acpi.madt.as_ref()) {
+        Some(madt) if is_supported() && !madt.io_apics.is_empty() => madt,
+        _ => return false,
+    };
+
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE;
+    if !memory::identity_map_region(madt.local_apic_address, MMIO_SIZE, flags) {
+        println!("APIC: local APIC at {:#x} collides with a kernel mapping", madt.local_apic_address);
+        return false;
+    }
+    LOCAL_APIC_BASE.store(madt.local_apic_address, Ordering::SeqCst);
+    init_local_apic();
+
+    let mut io_apics = IO_APICS.lock();
+    for (slot, description) in io_apics.iter_mut().zip(madt.io_apics.iter()) {
+        if !memory::identity_map_region(description.address, MMIO_SIZE, flags) {
+            println!("APIC: I/O APIC at {:#x} collides with a kernel mapping", description.address);
+            continue;
+        }
+        let mut io_apic = IoApic {
+            base: description.address,
+            gsi_base: description.gsi_base,
+            input_count: 0,
+        };
+        io_apic.input_count = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        for input in 0..io_apic.input_count {
+            io_apic.write_redirection(input, REDIRECTION_MASKED);
+        }
+        *slot = Some(io_apic);
+    }
+    true
+}
+
+/// Enables the local APIC of the current CPU. Called by `init` for the
+/// bootstrap processor.
+pub fn init_local_apic() {
+    unsafe {
+        write_local(LAPIC_TASK_PRIORITY, 0);
+        write_local(LAPIC_SPURIOUS, LAPIC_ENABLE | u32::from(SPURIOUS_INTERRUPT_ID));
+    }
+}
+
+/// Returns the ID of the local APIC of the current CPU.
+pub fn local_apic_id() -> u8 {
+    unsafe { (read_local(LAPIC_ID) >> 24) as u8 }
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    unsafe { write_local(LAPIC_EOI, 0) }
+}
+
+/// Sends an INIT inter-processor interrupt, which resets the target CPU
+/// into the wait-for-SIPI state.
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT);
+}
+
+/// Sends a startup inter-processor interrupt, which makes a CPU in the
+/// wait-for-SIPI state execute real mode code at `page * 4096`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | u32::from(page));
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    unsafe {
+        write_local(LAPIC_ICR_HIGH, u32::from(apic_id) << 24);
+        write_local(LAPIC_ICR_LOW, command);
+        while read_local(LAPIC_ICR_LOW) & ICR_SEND_PENDING != 0 {}
+    }
+}
+
+/// Routes the given ISA IRQ to `vector` on the bootstrap processor, using
+/// the interrupt source overrides of the MADT.
+pub fn route_irq(irq: u8, vector: u8) {
+    let (gsi, mut flags) = gsi_of_irq(irq);
+    flags |= u64::from(vector) | (u64::from(local_apic_id()) << 56);
+    with_io_apic_of(gsi, |io_apic, input| io_apic.write_redirection(input, flags));
+}
+
+/// Masks the given ISA IRQ in the I/O APIC.
+pub fn mask_irq(irq: u8) {
+    let (gsi, _) = gsi_of_irq(irq);
+    with_io_apic_of(gsi, |io_apic, input| io_apic.write_redirection(input, REDIRECTION_MASKED));
+}
+
+/// Starts the timer of the local APIC with the given initial count. It fires
+/// `vector` whenever it reaches zero if `periodic` is set, else it stops at
+/// zero without firing.
+pub fn start_timer(initial_count: u32, periodic: Option<u8>) {
+    let lvt = match periodic {
+        Some(vector) => LVT_TIMER_PERIODIC | u32::from(vector),
+        None => LVT_MASKED,
+    };
+    unsafe {
+        write_local(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write_local(LAPIC_LVT_TIMER, lvt);
+        write_local(LAPIC_TIMER_INITIAL_COUNT, initial_count);
+    }
+}
+
+/// Returns the current count of the local APIC timer.
+pub fn timer_count() -> u32 {
+    unsafe { read_local(LAPIC_TIMER_CURRENT_COUNT) }
+}
+
+/// Returns the global system interrupt of an ISA IRQ and the polarity and
+/// trigger mode bits for its redirection entry.
+fn gsi_of_irq(irq: u8) -> (u32, u64) {
+    let madt = acpi::tables().and_then(|acpi| acpi.madt.as_ref());
+    match madt.and_then(|madt| madt.interrupt_override(irq)) {
+        Some(o) => {
+            let mut flags = 0;
+            if o.active_low {
+                flags |= REDIRECTION_ACTIVE_LOW;
+            }
+            if o.level_triggered {
+                flags |= REDIRECTION_LEVEL_TRIGGERED;
+            }
+            (o.gsi, flags)
+        }
+        // ISA interrupts are active high and edge triggered by default
+        None => (u32::from(irq), 0),
+    }
+}
+
+/// Runs `f` with the I/O APIC that handles `gsi` and the index of its input.
+fn with_io_apic_of<F>(gsi: u32, f: F)
+where
+    F: FnOnce(&mut IoApic, u32),
+{
+    let mut io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .iter_mut()
+        .filter_map(|slot| slot.as_mut())
+        .find(|io_apic| gsi >= io_apic.gsi_base && gsi < io_apic.gsi_base + io_apic.input_count)
+        .expect("no I/O APIC handles the interrupt");
+    let input = gsi - io_apic.gsi_base;
+    f(io_apic, input)
+}
+
+unsafe fn read_local(register: usize) -> u32 {
+    let base = LOCAL_APIC_BASE.load(Ordering::SeqCst);
+    assert!(base != 0, "local APIC not initialized");
+    ptr::read_volatile((base + register) as *const u32)
+}
+
+unsafe fn write_local(register: usize, value: u32) {
+    let base = LOCAL_APIC_BASE.load(Ordering::SeqCst);
+    assert!(base != 0, "local APIC not initialized");
+    ptr::write_volatile((base + register) as *mut u32, value);
+}
+
+/// An I/O APIC, which routes external interrupts to the local APICs.
+#[derive(Clone, Copy)]
+struct IoApic {
+    /// Virtual (identity mapped) address of the registers
+    base: usize,
+    /// First global system interrupt of this I/O APIC
+    gsi_base: u32,
+    /// Number of inputs, i.e. redirection entries
+    input_count: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile(self.base as *mut u32, register);
+            ptr::read_volatile((self.base + 0x10) as *const u32)
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile(self.base as *mut u32, register);
+            ptr::write_volatile((self.base + 0x10) as *mut u32, value);
+        }
+    }
+
+    fn write_redirection(&mut self, input: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + input * 2;
+        // mask the entry while its halves don't match
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
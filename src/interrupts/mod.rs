@@ -0,0 +1,234 @@
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cpuio::Port;
+use pic8259_simple::ChainedPics;
+use smp::percpu;
+use spin::{self, Mutex};
+use x86_64::structures::idt::{ExceptionStackFrame, HandlerFunc, InterruptDescriptorTable};
+
+pub mod apic;
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+pub static PICS: spin::Mutex<ChainedPics> =
+    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Number of ISA IRQ lines. They use the vectors from `PIC_1_OFFSET` on with
+/// either interrupt controller.
+pub const IRQ_COUNT: usize = 16;
+
+// Hardware Timer uses line 0 of master PIC
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+
+pub const TIMER_INTERRUPT_ID: u8 = PIC_1_OFFSET + TIMER_IRQ;
+
+/// Vector of the spurious interrupts of the local APIC
+pub const SPURIOUS_INTERRUPT_ID: u8 = 0xff;
+
+/// A function that is called from the interrupt handler of an IRQ. The end
+/// of interrupt is already signaled when it runs, so it may switch to
+/// another thread. Interrupts stay disabled until it returns.
+pub type IrqHandler = fn();
+
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+/// Set once `enable_apic` replaced the PICs with the APICs.
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Registers `handler` for the given IRQ and unmasks the IRQ. Returns the
+/// previously registered handler.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Option<IrqHandler> {
+    // the handlers are locked from the interrupt handlers too
+    without_interrupts(|| {
+        let previous = mem::replace(&mut IRQ_HANDLERS.lock()[irq as usize], Some(handler));
+        if apic_enabled() {
+            apic::route_irq(irq, PIC_1_OFFSET + irq);
+        } else {
+            set_pic_mask(irq, false);
+        }
+        previous
+    })
+}
+
+/// Removes the handler of the given IRQ and masks the IRQ.
+pub fn unregister_irq(irq: u8) -> Option<IrqHandler> {
+    without_interrupts(|| {
+        let previous = IRQ_HANDLERS.lock()[irq as usize].take();
+        if apic_enabled() {
+            apic::mask_irq(irq);
+        } else {
+            set_pic_mask(irq, true);
+        }
+        previous
+    })
+}
+
+/// Returns `true` if the interrupts are delivered through the APICs.
+pub fn apic_enabled() -> bool {
+    APIC_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Replaces the 8259 PICs with the local and I/O APICs if the CPU supports
+/// them and the ACPI tables describe them. The registered IRQs are routed
+/// through the I/O APIC afterwards. Must be called after `acpi::init`.
+/// Returns `false` if the PICs stay in use.
+pub fn enable_apic() -> bool {
+    without_interrupts(|| {
+        if apic_enabled() || !apic::init() {
+            return apic_enabled();
+        }
+        disable_pics();
+        APIC_ENABLED.store(true, Ordering::SeqCst);
+
+        let handlers = *IRQ_HANDLERS.lock();
+        for (irq, handler) in handlers.iter().enumerate() {
+            if handler.is_some() {
+                apic::route_irq(irq as u8, PIC_1_OFFSET + irq as u8);
+            }
+        }
+        true
+    })
+}
+
+/// Masks every line of both PICs. They keep their vector offsets, so that
+/// spurious interrupts don't look like exceptions.
+fn disable_pics() {
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+/// Masks or unmasks `irq` in the PICs. Unmasking an IRQ of the slave PIC
+/// unmasks line 2 of the master PIC too, which the slave is chained to.
+fn set_pic_mask(irq: u8, masked: bool) {
+    const CASCADE_LINE: u8 = 2;
+
+    unsafe {
+        let (mut port, line) = if irq < 8 {
+            (Port::<u8>::new(0x21), irq)
+        } else {
+            (Port::<u8>::new(0xa1), irq - 8)
+        };
+        let mask = port.read();
+        port.write(if masked { mask | 1 << line } else { mask & !(1 << line) });
+
+        if irq >= 8 && !masked {
+            let mut master = Port::<u8>::new(0x21);
+            let mask = master.read();
+            master.write(mask & !(1 << CASCADE_LINE));
+        }
+    }
+}
+
+/// Returns `true` if `irq` is a spurious IRQ 7 or 15 of the PICs. A PIC
+/// raises its lowest priority line when an interrupt disappears before it is
+/// acknowledged, without setting the bit in the in-service register (ISR).
+fn is_spurious_pic_irq(irq: u8) -> bool {
+    const READ_ISR: u8 = 0x0b;
+
+    let mut command = match irq {
+        7 => unsafe { Port::<u8>::new(0x20) },
+        15 => unsafe { Port::<u8>::new(0xa0) },
+        _ => return false,
+    };
+    unsafe {
+        command.write(READ_ISR);
+        command.read() & (1 << 7) == 0
+    }
+}
+
+/// Signals the end of the given IRQ to the active interrupt controller.
+pub fn end_of_interrupt(irq: u8) {
+    if apic_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        // PIC expects an explicit "end of interrupt" (EOI) signal
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq) }
+    }
+}
+
+/// Installs the IRQ handlers in the given IDT.
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    let handlers: [HandlerFunc; IRQ_COUNT] = [
+        irq0_handler, irq1_handler, irq2_handler, irq3_handler,
+        irq4_handler, irq5_handler, irq6_handler, irq7_handler,
+        irq8_handler, irq9_handler, irq10_handler, irq11_handler,
+        irq12_handler, irq13_handler, irq14_handler, irq15_handler,
+    ];
+    for (irq, &handler) in handlers.iter().enumerate() {
+        idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(handler);
+    }
+    idt[usize::from(SPURIOUS_INTERRUPT_ID)].set_handler_fn(spurious_interrupt_handler);
+}
+
+/// Signals the end of the interrupt and runs the handler of the IRQ. Spurious
+/// IRQs of the PICs are dropped.
+fn dispatch(irq: u8) {
+    let _gs = percpu::KernelGs::enter();
+    if !apic_enabled() && is_spurious_pic_irq(irq) {
+        // the master PIC did see an interrupt on the cascade line of the
+        // slave, only the slave must not get an EOI
+        if irq == 15 {
+            unsafe { Port::<u8>::new(0x20).write(0x20) };
+        }
+        return;
+    }
+    // copy the handler out, so that it can (un)register handlers itself
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+    end_of_interrupt(irq);
+    if let Some(handler) = handler {
+        handler();
+    }
+}
+
+/// Defines the interrupt handler of an IRQ.
+macro_rules! irq_handler {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: &mut ExceptionStackFrame) {
+            dispatch($irq);
+        }
+    };
+}
+
+irq_handler!(irq0_handler, 0);
+irq_handler!(irq1_handler, 1);
+irq_handler!(irq2_handler, 2);
+irq_handler!(irq3_handler, 3);
+irq_handler!(irq4_handler, 4);
+irq_handler!(irq5_handler, 5);
+irq_handler!(irq6_handler, 6);
+irq_handler!(irq7_handler, 7);
+irq_handler!(irq8_handler, 8);
+irq_handler!(irq9_handler, 9);
+irq_handler!(irq10_handler, 10);
+irq_handler!(irq11_handler, 11);
+irq_handler!(irq12_handler, 12);
+irq_handler!(irq13_handler, 13);
+irq_handler!(irq14_handler, 14);
+irq_handler!(irq15_handler, 15);
+
+/// Spurious interrupts of the local APIC must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut ExceptionStackFrame) {}
+
+/// Runs `f` with interrupts disabled and restores the previous state after.
+/// Needed when taking a lock that is also taken by an interrupt handler.
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    use x86_64::instructions::interrupts;
+    use x86_64::registers::rflags::{self, RFlags};
+
+    let enabled = rflags::read().contains(RFlags::INTERRUPT_FLAG);
+    if enabled {
+        interrupts::disable();
+    }
+    let result = f();
+    if enabled {
+        interrupts::enable();
+    }
+    result
+}
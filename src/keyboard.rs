@@ -107,14 +107,16 @@ impl Modifiers {
     }
 }
 
-/// Our keyboard state, including I/O port, pressed modifiers, etc
+/// The data port of the keyboard controller
+const DATA_PORT: u16 = 0x60;
+
+/// Our keyboard state: the pressed modifiers. The interrupt handler doesn't
+/// take this lock, see `read_scancode`.
 struct State {
-    port: cpuio::Port<u8>,
     modifiers: Modifiers,
 }
 
 static STATE: Mutex<State> = Mutex::new(State {
-    port: unsafe { cpuio::Port::new(0x60) },
     modifiers: Modifiers::new(),
 });
 
@@ -131,10 +133,17 @@ fn find_asii(scancode: u8) -> Option<u8> {
     }
 }
 
-/// Try to read a single input character
-pub fn read_char() -> Option<char> {
+/// Reads the scancode of the last key event from the controller. Called by
+/// the interrupt handler, so it must not take `STATE`, which `decode` holds
+/// with interrupts enabled.
+pub fn read_scancode() -> u8 {
+    unsafe { cpuio::Port::<u8>::new(DATA_PORT).read() }
+}
+
+/// Updates the modifiers with `scancode` and converts it to a character, if
+/// it is a key press we understand.
+pub fn decode(scancode: u8) -> Option<char> {
     let mut state = STATE.lock();
-    let scancode = state.port.read();
     state.modifiers.update(scancode);
 
     if let Some(ascii) = find_asii(scancode) {
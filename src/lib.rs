@@ -3,6 +3,9 @@
 #![feature(const_fn)]
 #![feature(ptr_internals)]
 #![feature(alloc, allocator_api, alloc_error_handler)]
+#![feature(asm)]
+#![feature(futures_api, pin, arbitrary_self_types)]
+#![cfg_attr(feature = "kernel-test", feature(used))]
 #![no_std] // don't link the Rust standard library
 
 extern crate spin;
@@ -23,32 +26,60 @@ extern crate rlibc;
 extern crate linked_list_allocator;
 
 use core::panic::PanicInfo;
-use x86_64::structures::idt::{ExceptionStackFrame, InterruptDescriptorTable};
-use linked_list_allocator::LockedHeap;
+use core::str;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use memory::slab_allocator::SlabAllocator;
 
+#[macro_use]
+mod serial;
+#[cfg(feature = "kernel-test")]
+#[macro_use]
+mod test_runner;
 mod gdt;
 #[macro_use]
 mod vga_buffer;
+mod acpi;
+mod elf;
+mod initrd;
 mod interrupts;
 mod keyboard;
-#[macro_use]
-mod serial;
+mod exceptions;
 mod memory;
+mod power;
+mod smp;
+mod syscall;
+mod task;
+mod thread;
+mod time;
+mod usermode;
+#[cfg(feature = "integration-test")]
+mod integration_tests;
 
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_INITIAL_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_MAX_SIZE: usize = 512 * 1024 * 1024; // 512 MiB
+
+/// Frequency of the timer interrupt in Hz
+pub const TIMER_FREQUENCY: usize = 1000;
+
+/// Size of the kernel stack that replaces the boot stack, in pages
+const KERNEL_STACK_PAGES: usize = 16;
 
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: SlabAllocator = SlabAllocator::empty();
 
 #[no_mangle] // don't mangle the name of this function
 pub extern "C" fn rust_main(multiboot_information_address: usize) {
-    gdt::init();
+    gdt::init_boot();
     init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::register_irq(interrupts::TIMER_IRQ, timer_interrupt_handler);
+    interrupts::register_irq(interrupts::KEYBOARD_IRQ, keyboard_interrupt_handler);
+    time::pit::init(TIMER_FREQUENCY);
     x86_64::instructions::interrupts::enable();
 
     vga_buffer::clear_screen();
+    println!("boot time: {}", time::init_wall_clock());
 
     // Get boot info from multiboot / GRUB
     let boot_info = unsafe { multiboot2::load(multiboot_information_address) };
@@ -61,9 +92,59 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) {
 
     // init the heap allocator
     unsafe {
-        HEAP_ALLOCATOR.lock().init(HEAP_START, HEAP_START + HEAP_SIZE);
+        HEAP_ALLOCATOR.init(HEAP_START, HEAP_INITIAL_SIZE, HEAP_MAX_SIZE);
     }
-    
+    initrd::init();
+
+    // replace the boot GDT, the new interrupt stacks have guard pages
+    let cpu_tables = gdt::init();
+
+    // switch to the APICs if the firmware describes them
+    match acpi::init() {
+        Some(acpi) => {
+            print!("ACPI revision {}, tables:", acpi.revision);
+            for signature in &acpi.signatures {
+                print!(" {}", str::from_utf8(signature).unwrap_or("????"));
+            }
+            println!();
+        }
+        None => println!("ACPI: no RSDP found"),
+    }
+    let apic_id = if interrupts::enable_apic() {
+        time::apic_timer::init(TIMER_FREQUENCY);
+        println!("interrupts: APIC, tick source: APIC timer");
+        interrupts::apic::local_apic_id()
+    } else {
+        println!("interrupts: 8259 PIC, tick source: PIT");
+        0
+    };
+    smp::percpu::init(0, apic_id, cpu_tables);
+    syscall::init();
+    println!("{} CPUs running", smp::boot_application_processors());
+
+    // switch to a kernel stack with a guard page, the boot stack has none
+    let stack = memory::alloc_stack(KERNEL_STACK_PAGES).expect("could not allocate kernel stack");
+    unsafe {
+        asm!("mov rsp, $0
+              mov rdi, $0
+              call $1"
+             :: "r"(stack.top()), "r"(kernel_main as usize)
+             : "memory" : "intel", "volatile");
+    }
+    unreachable!();
+}
+
+/// Continues the boot process on the kernel stack allocated by `rust_main`,
+/// which ends at `stack_top`.
+extern "C" fn kernel_main(stack_top: usize) -> ! {
+    // the code from here on is the boot thread
+    thread::init(stack_top);
+
+    #[cfg(feature = "kernel-test")]
+    test_runner::run_tests();
+    #[cfg(feature = "integration-test")]
+    integration_tests::run();
+
     use alloc::boxed::Box;
     let mut heap_test = Box::new(42);
     *heap_test -= 15;
@@ -82,27 +163,41 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) {
         format!("Some String");
     }
     
+    let stats = memory::frame_stats();
+    println!("frames: {} used, {} free", stats.used(), stats.free);
+    println!("heap size: {} KiB", HEAP_ALLOCATOR.size() / 1024);
+    for class in HEAP_ALLOCATOR.stats().iter().filter(|c| c.allocations > 0) {
+        println!(
+            "slab {:>4}: {} allocs, {} frees",
+            class.size, class.allocations, class.deallocations
+        );
+    }
+
+    println!("uptime: {} ms", time::uptime() / 1_000_000);
+    println!("now: {}", time::now());
+
     println!("READY!");
 
-    loop {}
+    usermode::spawn_embedded_program();
+    if let Some(image) = initrd::find("init") {
+        if let Err(error) = usermode::spawn_program(image, &["init"], &[]) {
+            println!("could not start init: {:?}", error);
+        }
+    }
+
+    // the boot thread runs the async tasks from here on
+    let mut executor = task::Executor::new();
+    executor.spawn(task::Task::new(task::keyboard::print_keypresses()));
+    executor.run();
 }
 
 /// Create Interrupt Description Table
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        let timer_interrupt_id = usize::from(interrupts::TIMER_INTERRUPT_ID);
-        let keyboard_interrupt_id = usize::from(interrupts::KEYBOARD_INTERRUPT_ID);
-
-        idt[timer_interrupt_id].set_handler_fn(timer_interrupt_handler);
-        idt[keyboard_interrupt_id].set_handler_fn(keyboard_interrupt_handler);
-
+        exceptions::set_handlers(&mut idt);
+        interrupts::set_handlers(&mut idt);
+        syscall::set_handlers(&mut idt);
         idt
     };
 }
@@ -112,52 +207,18 @@ pub fn init_idt() {
     IDT.load();
 }
 
-/// Create Exception Breakpoint handler
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-/// Create Timer Interrupt handler
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut ExceptionStackFrame) {
-    // PIC expects an explicit "end of interrupt" (EOI) signal
-    unsafe {
-        interrupts::PICS
-            .lock()
-            .notify_end_of_interrupt(interrupts::TIMER_INTERRUPT_ID)
-    }
-}
-
-/// Create Keyboard Interrupt handler
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut ExceptionStackFrame) {
-    if let Some(input) = keyboard::read_char() {
-        if input == '\r' {
-            println!("");
-        } else if input == '\t' {
-            print!("    ");
-        } else if input as usize == 0 {
-            vga_buffer::backspace();
-        } else if input as usize == 27 {
-            // TODO ESC
-        } else {
-            print!("{}", input);
-        }
-    }
-
-    // PIC expects an explicit "end of interrupt" (EOI) signal
-    unsafe {
-        interrupts::PICS
-            .lock()
-            .notify_end_of_interrupt(interrupts::KEYBOARD_INTERRUPT_ID)
-    }
+/// Timer IRQ handler, the end of interrupt is signaled by `interrupts`
+fn timer_interrupt_handler() {
+    time::tick();
+    task::timer::tick();
+    // may switch to another thread
+    thread::tick();
 }
 
-/// Create Double Fault handler
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut ExceptionStackFrame,
-    error_code: u64,
-) {
-    println!("EXCEPTION: DOUBLE FAULT {}\n{:#?}", error_code, stack_frame);
-    loop {}
+/// Keyboard IRQ handler, the end of interrupt is signaled by `interrupts`.
+/// The scancode is handled by the async keyboard task.
+fn keyboard_interrupt_handler() {
+    task::keyboard::add_scancode(keyboard::read_scancode());
 }
 
 fn enable_nxe_bit() {
@@ -174,13 +235,23 @@ fn enable_write_protect_bit() {
     }
 }
 
-pub unsafe fn exit_qemu() {
+/// Exit codes reported through the `isa-debug-exit` device. QEMU exits with
+/// `(code << 1) | 1`, so success becomes 33 and failure 35.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub unsafe fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::<u32>::new(0xf4);
-    port.write(0);
+    port.write(exit_code as u32);
 }
 
+#[cfg(not(any(feature = "kernel-test", feature = "integration-test")))]
 #[panic_implementation]
 #[no_mangle]
 /// This function is called on panic.
@@ -189,8 +260,24 @@ pub fn panic(info: &PanicInfo) -> ! {
     loop {}
 }
 
+#[cfg(any(feature = "kernel-test", feature = "integration-test"))]
+#[panic_implementation]
+#[no_mangle]
+/// This function is called on panic. A panic means a failed test.
+pub fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]");
+    serial_println!("{}", info);
+    unsafe {
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
 #[alloc_error_handler]
 #[no_mangle]
 pub fn oom(layout: core::alloc::Layout) -> ! {
+    #[cfg(feature = "itest-heap-oom")]
+    integration_tests::heap_oom::out_of_memory(layout);
+
     panic!("Out of memory: {:?}", layout);
 }
@@ -0,0 +1,210 @@
+use memory::{align_frame_number, Frame, FrameAllocator, PAGE_SIZE};
+use multiboot2::MemoryAreaIter;
+
+/// Highest physical address the bitmap can describe (4 GiB).
+const MAX_PHYSICAL_MEMORY: usize = 4 * 1024 * 1024 * 1024;
+const MAX_FRAMES: usize = MAX_PHYSICAL_MEMORY / PAGE_SIZE;
+const BITS_PER_WORD: usize = 64;
+
+/// Backing storage for the bitmap. One bit per frame, a set bit means the
+/// frame is free. It lives in `.bss`, so it is covered by the kernel range and
+/// starts out with every frame marked as used.
+static mut BITMAP: [u64; MAX_FRAMES / BITS_PER_WORD] = [0; MAX_FRAMES / BITS_PER_WORD];
+
+/// Backing storage for the second bitmap. A set bit means the frame belongs
+/// to the allocator: it is in a usable memory area and wasn't reserved, so
+/// it may be deallocated.
+static mut MANAGED: [u64; MAX_FRAMES / BITS_PER_WORD] = [0; MAX_FRAMES / BITS_PER_WORD];
+
+/// Usage statistics of a `BitmapFrameAllocator`.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Number of usable frames reported by the memory map
+    pub total: usize,
+    /// Number of frames that are currently free
+    pub free: usize,
+}
+
+impl FrameStats {
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+}
+
+/// A frame allocator that tracks every frame of the multiboot memory areas in
+/// a bitmap, so that deallocated frames can be handed out again.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// The frames that the allocator hands out, see `MANAGED`
+    managed: &'static mut [u64],
+    /// Number of frames covered by the bitmap (highest usable frame + 1)
+    frame_count: usize,
+    total_frames: usize,
+    free_frames: usize,
+    /// Word index where the next search starts
+    next_word: usize,
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        let word_count = self.word_count();
+        for i in 0..word_count {
+            let index = (self.next_word + i) % word_count;
+            let word = self.bitmap[index];
+            if word != 0 {
+                let number = index * BITS_PER_WORD + word.trailing_zeros() as usize;
+                self.mark_used(number);
+                self.next_word = index;
+                return Some(Frame { number: number });
+            }
+        }
+        None
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(
+            frame.number < self.frame_count && self.is_managed(frame.number),
+            "frame {:#x} is not managed by this allocator",
+            frame.start_address()
+        );
+        assert!(!self.is_free(frame.number), "double free of frame {:#x}", frame.start_address());
+        self.mark_free(frame.number);
+    }
+
+    fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<(Frame, Frame)> {
+        assert!(count > 0, "can't allocate an empty frame range");
+        let mut start = align_frame_number(0, align);
+        while start + count <= self.frame_count {
+            let used_frame = (start..start + count).find(|&number| !self.is_free(number));
+            match used_frame {
+                Some(used) => {
+                    // continue behind the used frame
+                    start = align_frame_number(used + 1, align);
+                }
+                None => {
+                    for number in start..start + count {
+                        self.mark_used(number);
+                    }
+                    return Some((
+                        Frame { number: start },
+                        Frame {
+                            number: start + count - 1,
+                        },
+                    ));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl BitmapFrameAllocator {
+    /// Creates the allocator from the multiboot memory areas and reserves the
+    /// kernel and multiboot ranges. Must be called only once because it takes
+    /// ownership of the static bitmap.
+    pub fn new(
+        kernel_start: usize,
+        kernel_end: usize,
+        multiboot_start: usize,
+        multiboot_end: usize,
+        memory_areas: MemoryAreaIter,
+    ) -> BitmapFrameAllocator {
+        assert_has_not_been_called!("BitmapFrameAllocator::new must be called only once");
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap: unsafe { &mut BITMAP },
+            managed: unsafe { &mut MANAGED },
+            frame_count: 0,
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+
+        for area in memory_areas {
+            let start_frame = Frame::containing_address(area.start_address() as usize);
+            let end_frame = Frame::containing_address((area.start_address() + area.size() - 1) as usize);
+            if start_frame.number >= MAX_FRAMES {
+                // the bitmap can't describe this area
+                continue;
+            }
+            let end_number = if end_frame.number < MAX_FRAMES {
+                end_frame.number
+            } else {
+                MAX_FRAMES - 1
+            };
+            for number in start_frame.number..end_number + 1 {
+                if !allocator.is_free(number) {
+                    allocator.mark_free(number);
+                    allocator.set_managed(number, true);
+                    allocator.total_frames += 1;
+                }
+            }
+            if end_number + 1 > allocator.frame_count {
+                allocator.frame_count = end_number + 1;
+            }
+        }
+
+        allocator.reserve_range(
+            Frame::containing_address(kernel_start),
+            Frame::containing_address(kernel_end),
+        );
+        allocator.reserve_range(
+            Frame::containing_address(multiboot_start),
+            Frame::containing_address(multiboot_end),
+        );
+
+        allocator
+    }
+
+    /// Marks all frames between `start` and `end` (inclusive) as used, so
+    /// they are never handed out, and rejects deallocating them. Frames
+    /// outside of the usable areas are ignored. Must be called before the
+    /// frames in the range are allocated.
+    pub fn reserve_range(&mut self, start: Frame, end: Frame) {
+        for frame in Frame::range_inclusive(start, end) {
+            if frame.number < self.frame_count && self.is_free(frame.number) {
+                self.mark_used(frame.number);
+                self.set_managed(frame.number, false);
+            }
+        }
+    }
+
+    /// Returns the current usage statistics.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total_frames,
+            free: self.free_frames,
+        }
+    }
+
+    fn word_count(&self) -> usize {
+        (self.frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD
+    }
+
+    fn is_free(&self, number: usize) -> bool {
+        self.bitmap[number / BITS_PER_WORD] & (1 << (number % BITS_PER_WORD)) != 0
+    }
+
+    fn is_managed(&self, number: usize) -> bool {
+        self.managed[number / BITS_PER_WORD] & (1 << (number % BITS_PER_WORD)) != 0
+    }
+
+    fn set_managed(&mut self, number: usize, managed: bool) {
+        let bit = 1 << (number % BITS_PER_WORD);
+        if managed {
+            self.managed[number / BITS_PER_WORD] |= bit;
+        } else {
+            self.managed[number / BITS_PER_WORD] &= !bit;
+        }
+    }
+
+    fn mark_free(&mut self, number: usize) {
+        self.bitmap[number / BITS_PER_WORD] |= 1 << (number % BITS_PER_WORD);
+        self.free_frames += 1;
+    }
+
+    fn mark_used(&mut self, number: usize) {
+        self.bitmap[number / BITS_PER_WORD] &= !(1 << (number % BITS_PER_WORD));
+        self.free_frames -= 1;
+    }
+}
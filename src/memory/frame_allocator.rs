@@ -1,4 +1,4 @@
-use memory::{Frame, FrameAllocator};
+use memory::{align_frame_number, Frame, FrameAllocator};
 use multiboot2::{MemoryArea, MemoryAreaIter};
 
 pub struct AreaFrameAllocator {
@@ -54,6 +54,44 @@ impl FrameAllocator for AreaFrameAllocator {
     fn deallocate_frame(&mut self, _frame: Frame) {
         unimplemented!()
     }
+
+    fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<(Frame, Frame)> {
+        assert!(count > 0, "can't allocate an empty frame range");
+        while let Some(area) = self.current_area {
+            let start = align_frame_number(self.next_free_frame.number, align);
+            let end = start + count - 1;
+
+            // the last frame of the current area
+            let current_area_last_frame = {
+                let address = area.start_address() + area.size() - 1;
+                Frame::containing_address(address as usize)
+            };
+
+            if end > current_area_last_frame.number {
+                // the run doesn't fit into the current area, switch to next area
+                self.next_free_frame = Frame {
+                    number: current_area_last_frame.number + 1,
+                };
+                self.choose_next_area();
+            } else if start <= self.kernel_end.number && self.kernel_start.number <= end {
+                // the run overlaps the kernel
+                self.next_free_frame = Frame {
+                    number: self.kernel_end.number + 1,
+                };
+            } else if start <= self.multiboot_end.number && self.multiboot_start.number <= end {
+                // the run overlaps the multiboot info structure
+                self.next_free_frame = Frame {
+                    number: self.multiboot_end.number + 1,
+                };
+            } else {
+                // frames skipped for alignment are lost, like everything else
+                // this allocator hands out
+                self.next_free_frame = Frame { number: end + 1 };
+                return Some((Frame { number: start }, Frame { number: end }));
+            }
+        }
+        None
+    }
 }
 
 impl AreaFrameAllocator {
@@ -94,3 +132,56 @@ impl AreaFrameAllocator {
         }
     }
 }
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::AreaFrameAllocator;
+    use memory::{self, Frame, FrameAllocator};
+
+    /// Creates an allocator over the real memory map. It must only be used
+    /// to inspect the returned frames, since they are managed by the global
+    /// frame allocator.
+    fn new_allocator() -> AreaFrameAllocator {
+        let boot_info = memory::boot_info();
+        let (kernel_start, kernel_end) = memory::kernel_range(&boot_info);
+        AreaFrameAllocator::new(
+            kernel_start,
+            kernel_end,
+            boot_info.start_address(),
+            boot_info.end_address(),
+            boot_info.memory_map_tag().unwrap().memory_areas(),
+        )
+    }
+
+    kernel_test!(area_allocator_skips_reserved_frames {
+        let boot_info = memory::boot_info();
+        let (kernel_start, kernel_end) = memory::kernel_range(&boot_info);
+        let kernel_start = Frame::containing_address(kernel_start);
+        let kernel_end = Frame::containing_address(kernel_end);
+        let multiboot_start = Frame::containing_address(boot_info.start_address());
+        let multiboot_end = Frame::containing_address(boot_info.end_address());
+
+        let mut allocator = new_allocator();
+        let mut last_frame: Option<Frame> = None;
+        for _ in 0..1024 {
+            let frame = allocator.allocate_frame().expect("no frames available");
+            assert!(frame < kernel_start || frame > kernel_end);
+            assert!(frame < multiboot_start || frame > multiboot_end);
+            if let Some(ref last_frame) = last_frame {
+                assert!(frame > *last_frame);
+            }
+            last_frame = Some(frame);
+        }
+    });
+
+    kernel_test!(area_allocator_contiguous_is_aligned {
+        let mut allocator = new_allocator();
+        // make sure the next free frame is not aligned
+        allocator.allocate_frame();
+
+        let (start, end) = allocator.allocate_contiguous(8, 8).expect("no frames available");
+        assert_eq!(start.number % 8, 0);
+        assert_eq!(end.number - start.number, 7);
+        assert!(allocator.allocate_frame().unwrap() > end);
+    });
+}
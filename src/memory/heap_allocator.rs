@@ -1,47 +1,9 @@
 use core::alloc::{GlobalAlloc,Layout};
-use core::sync::atomic::{AtomicUsize, Ordering};
-use oom;
-
-/// A simple allocator that allocates memory linearly and ignores freed memory.
-#[derive(Debug)]
-pub struct BumpAllocator {
-    heap_start: usize,
-    heap_end: usize,
-    next: AtomicUsize,
-}
-
-impl BumpAllocator {
-    pub const fn new(heap_start: usize, heap_end: usize) -> Self {
-        Self { heap_start, heap_end, next: AtomicUsize::new(heap_start) }
-    }
-}
-
-unsafe impl GlobalAlloc for BumpAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        loop {
-            // load current state of the `next` field
-            let current_next = self.next.load(Ordering::Relaxed);
-            let alloc_start = align_up(current_next, layout.align());
-            let alloc_end = alloc_start.saturating_add(layout.size());
-
-            if alloc_end <= self.heap_end {
-                // update the `next` pointer if it still has the value `current_next`
-                let next_now = self.next.compare_and_swap(current_next, alloc_end,
-                    Ordering::Relaxed);
-                if next_now == current_next {
-                    // next address was successfully updated, allocation succeeded
-                    return alloc_start as *mut u8;
-                }
-            } else {
-                oom(layout);
-            }
-        }
-    }
-
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        // do nothing, leak memory
-    }
-}
+use core::cmp;
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+use memory::{self, PAGE_SIZE};
+use spin::Mutex;
 
 /// Align downwards. Returns the greatest x with alignment `align`
 /// so that x <= addr. The alignment must be a power of 2.
@@ -60,3 +22,83 @@ pub fn align_down(addr: usize, align: usize) -> usize {
 pub fn align_up(addr: usize, align: usize) -> usize {
     align_down(addr + align - 1, align)
 }
+
+/// Size by which the heap grows at least, to avoid mapping single pages.
+const MIN_GROW_SIZE: usize = 16 * PAGE_SIZE;
+
+/// A linked list heap that reserves a virtual range and maps new pages on
+/// demand when an allocation doesn't fit, up to a maximum size.
+pub struct GrowableHeap {
+    inner: Mutex<GrowableHeapInner>,
+}
+
+struct GrowableHeapInner {
+    heap: Heap,
+    max_size: usize,
+}
+
+impl GrowableHeap {
+    /// Creates an empty heap. `init` must be called before it is used.
+    pub const fn empty() -> Self {
+        GrowableHeap {
+            inner: Mutex::new(GrowableHeapInner {
+                heap: Heap::empty(),
+                max_size: 0,
+            }),
+        }
+    }
+
+    /// Initializes the heap with the already mapped region
+    /// `heap_start..heap_start + initial_size`. The heap may grow up to
+    /// `max_size` bytes, so the virtual range up to `heap_start + max_size`
+    /// must not be used for anything else.
+    pub unsafe fn init(&self, heap_start: usize, initial_size: usize, max_size: usize) {
+        assert!(initial_size <= max_size);
+        let mut inner = self.inner.lock();
+        inner.heap.init(heap_start, initial_size);
+        inner.max_size = max_size;
+    }
+
+    /// Returns the number of bytes that are currently mapped for the heap.
+    pub fn size(&self) -> usize {
+        self.inner.lock().heap.size()
+    }
+}
+
+impl GrowableHeapInner {
+    /// Maps at least `size` more bytes at the top of the heap. Returns `false`
+    /// if the maximum size is reached or there is not enough physical memory.
+    fn grow(&mut self, size: usize) -> bool {
+        let available = self.max_size - self.heap.size();
+        let size = cmp::min(cmp::max(align_up(size, PAGE_SIZE), MIN_GROW_SIZE), available);
+        if size == 0 || !memory::grow_heap(self.heap.top(), size) {
+            return false;
+        }
+        unsafe {
+            self.heap.extend(size);
+        }
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut inner = self.inner.lock();
+        loop {
+            if let Ok(allocation) = inner.heap.allocate_first_fit(layout) {
+                return allocation.as_ptr();
+            }
+            // the new memory might need padding for the alignment
+            if !inner.grow(layout.size() + layout.align()) {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner
+            .lock()
+            .heap
+            .deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
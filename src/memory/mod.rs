@@ -1,14 +1,43 @@
+pub use self::bitmap_allocator::{BitmapFrameAllocator, FrameStats};
 pub use self::frame_allocator::AreaFrameAllocator;
-pub use self::paging::remap_the_kernel;
-use self::paging::PhysicalAddress;
-use multiboot2::BootInformation;
+pub use self::paging::{active_page_table, remap_the_kernel, switch_page_table};
+pub use self::paging::{AddressSpace, EntryFlags};
+pub use self::stack_allocator::Stack;
+use self::paging::{ActivePageTable, PhysicalAddress, VirtualAddress};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use multiboot2::{self, BootInformation};
+use spin::Mutex;
 
+mod bitmap_allocator;
 mod frame_allocator;
-// pub mod heap_allocator;
+pub mod heap_allocator;
 mod paging;
+pub mod slab_allocator;
+mod stack_allocator;
 
 pub const PAGE_SIZE: usize = 4096;
 
+/// The first MiB of physical memory is never handed out. It holds firmware
+/// data and the trampoline of the application processors (see `smp`).
+const LOW_MEMORY_SIZE: usize = 1024 * 1024;
+
+/// Size of the virtual range reserved for kernel stacks and their guard pages.
+const KERNEL_STACKS_SIZE: usize = 256 * 1024 * 1024; // 256 MiB
+
+/// Kernel pages right behind the stacks for accessing frames that aren't
+/// mapped in the active table: the `TemporaryPage` of the memory controller,
+/// two pages for `AddressSpace` and one for the tables `Mapper` creates when
+/// it splits huge pages.
+const TEMPORARY_PAGE: usize = ::HEAP_START + ::HEAP_MAX_SIZE + KERNEL_STACKS_SIZE;
+const SCRATCH_PAGE_A: usize = TEMPORARY_PAGE + PAGE_SIZE;
+const SCRATCH_PAGE_B: usize = TEMPORARY_PAGE + 2 * PAGE_SIZE;
+const SPLIT_PAGE: usize = TEMPORARY_PAGE + 3 * PAGE_SIZE;
+
+/// The end of the kernel's virtual windows: the heap, the kernel stacks and
+/// the pages above. They are mapped on demand, so `identity_map_region` must
+/// keep out of them even where nothing is mapped yet.
+const KERNEL_WINDOWS_END: usize = SPLIT_PAGE + PAGE_SIZE;
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Frame {
     number: usize,
@@ -27,11 +56,11 @@ impl Frame {
         }
     }
 
-    fn start_address(&self) -> PhysicalAddress {
+    pub fn start_address(&self) -> PhysicalAddress {
         self.number * PAGE_SIZE
     }
 
-    fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
+    pub fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
         FrameIter {
             start: start,
             end: end,
@@ -42,9 +71,28 @@ impl Frame {
 pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
     fn deallocate_frame(&mut self, frame: Frame);
+
+    /// Allocates `count` physically contiguous frames. The number of the
+    /// first frame is a multiple of `align`, which is given in frames and
+    /// must be a power of two (e.g. 512 for a 2MiB aligned run). Returns the
+    /// first and the last frame, suitable for `Frame::range_inclusive`.
+    fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<(Frame, Frame)>;
+
+    /// Deallocates all frames from `start` to `end` (inclusive).
+    fn deallocate_range(&mut self, start: Frame, end: Frame) {
+        for frame in Frame::range_inclusive(start, end) {
+            self.deallocate_frame(frame);
+        }
+    }
 }
 
-struct FrameIter {
+/// Rounds the frame number `number` up to the next multiple of `align`.
+fn align_frame_number(number: usize, align: usize) -> usize {
+    assert!(align.is_power_of_two(), "`align` must be a power of 2");
+    (number + align - 1) & !(align - 1)
+}
+
+pub struct FrameIter {
     start: Frame,
     end: Frame,
 }
@@ -63,13 +111,333 @@ impl Iterator for FrameIter {
     }
 }
 
-pub fn init(boot_info: &BootInformation) {
-    assert_has_not_been_called!("memory::init must be called only once");
-    
-    // Read Memory from BIOS
-    let memory_map_tag = boot_info
-        .memory_map_tag()
-        .expect("Memory map tag required");
+/// The memory controller created by `init`.
+///
+/// The heap grows through this controller, so code holding the lock must not
+/// allocate on the heap.
+static MEMORY_CONTROLLER: Mutex<Option<MemoryController>> = Mutex::new(None);
+
+/// Owns the active page table and the frame allocator after `init`.
+pub struct MemoryController {
+    active_table: ActivePageTable,
+    frame_allocator: BitmapFrameAllocator,
+    stack_allocator: stack_allocator::StackAllocator,
+    /// For editing inactive page tables, see `ActivePageTable::with`
+    temporary_page: paging::TemporaryPage,
+}
+
+impl MemoryController {
+    /// Returns the usage statistics of the physical frame allocator.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_allocator.stats()
+    }
+}
+
+/// Address of the multiboot information structure, set by `init`.
+static BOOT_INFO_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+/// Physical address of the kernel's P4 table, set by `init`.
+static KERNEL_PAGE_TABLE: AtomicUsize = AtomicUsize::new(0);
+
+/// The guard page below the boot stack, set by `paging::remap_the_kernel`.
+static BOOT_STACK_GUARD_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+const MAX_GUARD_PAGES: usize = 64;
+
+/// Start addresses of the unmapped guard pages and what they protect.
+static GUARD_PAGES: Mutex<[Option<(VirtualAddress, &'static str)>; MAX_GUARD_PAGES]> =
+    Mutex::new([None; MAX_GUARD_PAGES]);
+
+/// Remembers the unmapped page at `address` as a guard page, so that page
+/// faults on it can be reported as such. `description` names what the guard
+/// page protects. Returns `false` if there is no room left to remember it.
+pub fn register_guard_page(address: VirtualAddress, description: &'static str) -> bool {
+    let mut guard_pages = GUARD_PAGES.lock();
+    match guard_pages.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some((address & !(PAGE_SIZE - 1), description));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Forgets the guard page at `address`.
+pub fn unregister_guard_page(address: VirtualAddress) {
+    let address = address & !(PAGE_SIZE - 1);
+    for slot in GUARD_PAGES.lock().iter_mut() {
+        if slot.map(|(start, _)| start) == Some(address) {
+            *slot = None;
+        }
+    }
+}
+
+/// Returns the address of the guard page that `init` creates below the boot
+/// stack from the frame of the boot P4 table.
+#[allow(dead_code)]
+pub fn boot_stack_guard_page() -> VirtualAddress {
+    let address = BOOT_STACK_GUARD_PAGE.load(Ordering::SeqCst);
+    assert!(address != 0, "memory::init must be called first");
+    address
+}
+
+/// Returns the description of the guard page containing `address`, if any.
+pub fn guard_page_containing(address: VirtualAddress) -> Option<&'static str> {
+    let address = address & !(PAGE_SIZE - 1);
+    GUARD_PAGES
+        .lock()
+        .iter()
+        .filter_map(|slot| *slot)
+        .find(|&(start, _)| start == address)
+        .map(|(_, description)| description)
+}
+
+/// Prints the P4 to P1 entries that translate `address` in the active table
+/// to the screen and the serial interface. This doesn't lock the memory
+/// controller, so it can be used in exception handlers.
+pub fn print_page_walk(address: VirtualAddress) {
+    let mapper = unsafe { paging::Mapper::new() };
+    let levels = ["P4", "P3", "P2", "P1"];
+    for (level, entry) in levels.iter().zip(mapper.walk(address).iter()) {
+        match *entry {
+            Some(entry) => {
+                println!("  {} entry: {:?}", level, entry);
+                serial_println!("  {} entry: {:?}", level, entry);
+            }
+            None => break,
+        }
+    }
+}
+
+/// Translates `address` with the active table. Like `print_page_walk`, this
+/// doesn't lock the memory controller.
+pub fn translate(address: VirtualAddress) -> Option<PhysicalAddress> {
+    let mapper = unsafe { paging::Mapper::new() };
+    mapper.translate(address)
+}
+
+/// Returns the flags of the page containing `address` in the active table, or
+/// `None` if it is not mapped.
+pub fn page_flags(address: VirtualAddress) -> Option<EntryFlags> {
+    let mapper = unsafe { paging::Mapper::new() };
+    let walk = mapper.walk(address);
+    walk.iter()
+        .filter_map(|entry| *entry)
+        .last()
+        .filter(|entry| !entry.is_unused() && entry.flags().contains(EntryFlags::PRESENT))
+        .map(|entry| entry.flags())
+}
+
+/// Runs `f` with the global memory controller.
+pub fn with_memory_controller<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut MemoryController) -> R,
+{
+    let mut controller = MEMORY_CONTROLLER.lock();
+    f(controller.as_mut().expect("memory::init must be called first"))
+}
+
+/// Maps `size` bytes of fresh frames at `start` for the heap. Returns `false`
+/// without mapping anything if there aren't enough free frames.
+pub fn grow_heap(start: VirtualAddress, size: usize) -> bool {
+    use self::paging::Page;
+
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1);
+
+    with_memory_controller(|controller| {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ..
+        } = controller;
+
+        map_fresh_pages(active_table, frame_allocator, start_page, end_page, EntryFlags::WRITABLE)
+    })
+}
+
+/// Allocates a kernel stack of `size_in_pages` pages with a guard page below.
+pub fn alloc_stack(size_in_pages: usize) -> Option<Stack> {
+    with_memory_controller(|controller| {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut stack_allocator,
+            ..
+        } = controller;
+        stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages)
+    })
+}
+
+/// Unmaps a stack allocated by `alloc_stack` and frees its frames.
+pub fn free_stack(stack: Stack) {
+    with_memory_controller(|controller| {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut stack_allocator,
+            ..
+        } = controller;
+        stack_allocator.dealloc_stack(stack, active_table, frame_allocator)
+    })
+}
+
+/// Identity maps the physical range of `size` bytes at `start` with the given
+/// flags, for memory mapped devices and firmware tables. Their frames aren't
+/// handed out by the frame allocator. Pages that are already identity mapped
+/// are left untouched. Returns `false` without mapping anything if the range
+/// is empty, overlaps the kernel windows from `HEAP_START` on, reaches past
+/// P4 entry 0 (the only one shared with user address spaces), or if a page of
+/// it is mapped to another frame.
+pub fn identity_map_region(start: PhysicalAddress, size: usize, flags: EntryFlags) -> bool {
+    use usermode::USER_SPACE_START;
+
+    let end = match start.checked_add(size) {
+        Some(end) if size > 0 => end,
+        _ => return false,
+    };
+    if end > USER_SPACE_START || (start < KERNEL_WINDOWS_END && end > ::HEAP_START) {
+        return false;
+    }
+
+    let start_frame = Frame::containing_address(start);
+    let end_frame = Frame::containing_address(start + size - 1);
+
+    with_memory_controller(|controller| {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ..
+        } = controller;
+
+        let conflict = Frame::range_inclusive(start_frame.clone(), end_frame.clone()).any(|frame| {
+            let page = paging::Page::containing_address(frame.start_address());
+            match active_table.translate_page(page) {
+                Some(mapped) => mapped != frame,
+                None => false,
+            }
+        });
+        if conflict {
+            return false;
+        }
+
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            let page = paging::Page::containing_address(frame.start_address());
+            if active_table.translate_page(page).is_none() {
+                active_table.identity_map(frame, flags, frame_allocator);
+            }
+        }
+        true
+    })
+}
+
+/// Maps `size` bytes at `start` to fresh frames with the given flags.
+/// Returns `false` without mapping anything if there aren't enough free
+/// frames.
+pub fn map_region(start: VirtualAddress, size: usize, flags: EntryFlags) -> bool {
+    use self::paging::Page;
+
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1);
+
+    with_memory_controller(|controller| {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ..
+        } = controller;
+
+        map_fresh_pages(active_table, frame_allocator, start_page, end_page, flags)
+    })
+}
+
+/// Maps the pages from `start_page` to `end_page` to fresh frames. If the
+/// frames for the pages or their page tables run out, the pages mapped so far
+/// are unmapped again and `false` is returned.
+fn map_fresh_pages(
+    active_table: &mut ActivePageTable,
+    allocator: &mut BitmapFrameAllocator,
+    start_page: paging::Page,
+    end_page: paging::Page,
+    flags: EntryFlags,
+) -> bool {
+    for page in paging::Page::range_inclusive(start_page, end_page) {
+        let mapped = match allocator.allocate_frame() {
+            Some(frame) => match active_table.try_map_to(page, frame, flags, allocator) {
+                Ok(()) => true,
+                Err(frame) => {
+                    allocator.deallocate_frame(frame);
+                    false
+                }
+            },
+            None => false,
+        };
+        if !mapped {
+            if page > start_page {
+                let last_mapped = paging::Page::containing_address(page.start_address() - PAGE_SIZE);
+                for page in paging::Page::range_inclusive(start_page, last_mapped) {
+                    active_table.unmap(page, allocator);
+                }
+            }
+            return false;
+        }
+    }
+    true
+}
+
+/// Changes the flags of the mapped pages of `size` bytes at `start`.
+pub fn set_region_flags(start: VirtualAddress, size: usize, flags: EntryFlags) {
+    use self::paging::Page;
+
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1);
+
+    with_memory_controller(|controller| {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ..
+        } = controller;
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            active_table.update_flags(page, flags, frame_allocator);
+        }
+    })
+}
+
+/// Unmaps the pages of `size` bytes at `start` and frees their frames.
+pub fn unmap_region(start: VirtualAddress, size: usize) {
+    use self::paging::Page;
+
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1);
+
+    with_memory_controller(|controller| {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ..
+        } = controller;
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            active_table.unmap(page, frame_allocator);
+        }
+    })
+}
+
+/// Returns the usage statistics of the physical frame allocator.
+pub fn frame_stats() -> FrameStats {
+    with_memory_controller(|controller| controller.frame_stats())
+}
+
+/// Returns the physical address of the P4 table created by `init`. Threads
+/// without an `AddressSpace` run with it.
+pub fn kernel_page_table() -> PhysicalAddress {
+    KERNEL_PAGE_TABLE.load(Ordering::SeqCst)
+}
+
+/// Returns the start and end address of the loaded kernel sections.
+fn kernel_range(boot_info: &BootInformation) -> (usize, usize) {
     // Read Elf Sections from Kernel
     let elf_sections_tag = boot_info
         .elf_sections_tag()
@@ -88,6 +456,27 @@ pub fn init(boot_info: &BootInformation) {
         .max()
         .unwrap();
 
+    (kernel_start as usize, kernel_end as usize)
+}
+
+/// Returns the multiboot information that was passed to `init`. It stays
+/// identity mapped, so it can be loaded again at any time.
+pub fn boot_info() -> BootInformation {
+    let address = BOOT_INFO_ADDRESS.load(Ordering::SeqCst);
+    assert!(address != 0, "memory::init must be called first");
+    unsafe { multiboot2::load(address) }
+}
+
+pub fn init(boot_info: &BootInformation) {
+    assert_has_not_been_called!("memory::init must be called only once");
+    BOOT_INFO_ADDRESS.store(boot_info.start_address(), Ordering::SeqCst);
+    
+    // Read Memory from BIOS
+    let memory_map_tag = boot_info
+        .memory_map_tag()
+        .expect("Memory map tag required");
+    let (kernel_start, kernel_end) = kernel_range(boot_info);
+
     println!("kernel start: {:#x}, kernel end: {:#x}",
              kernel_start,
              kernel_end);
@@ -95,22 +484,57 @@ pub fn init(boot_info: &BootInformation) {
              boot_info.start_address(),
              boot_info.end_address());
 
-    let mut frame_allocator = AreaFrameAllocator::new(
-        kernel_start as usize,
-        kernel_end as usize,
+    let mut frame_allocator = BitmapFrameAllocator::new(
+        kernel_start,
+        kernel_end,
         boot_info.start_address(),
         boot_info.end_address(),
         memory_map_tag.memory_areas());
+    frame_allocator.reserve_range(
+        Frame::containing_address(0),
+        Frame::containing_address(LOW_MEMORY_SIZE - 1),
+    );
+    // the boot modules stay where GRUB loaded them, see `initrd`
+    for module in boot_info.module_tags() {
+        let (start, end) = (module.start_address() as usize, module.end_address() as usize);
+        if end > start {
+            println!("module {:?}: {:#x} - {:#x}", ::initrd::module_name(module.name()), start, end);
+            frame_allocator.reserve_range(
+                Frame::containing_address(start),
+                Frame::containing_address(end - 1),
+            );
+        }
+    }
 
     let mut active_table = paging::remap_the_kernel(&mut frame_allocator, &boot_info);
+    KERNEL_PAGE_TABLE.store(active_page_table(), Ordering::SeqCst);
 
     use self::paging::Page;
-    use {HEAP_START, HEAP_SIZE};
+    use {HEAP_INITIAL_SIZE, HEAP_MAX_SIZE, HEAP_START};
 
     let heap_start_page = Page::containing_address(HEAP_START);
-    let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE - 1);
+    let heap_end_page = Page::containing_address(HEAP_START + HEAP_INITIAL_SIZE - 1);
 
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
         active_table.map(page, paging::EntryFlags::WRITABLE, &mut frame_allocator);
     }
+
+    // the kernel stacks are placed right behind the reserved heap range
+    let stack_allocator = {
+        let stack_start_page = Page::containing_address(HEAP_START + HEAP_MAX_SIZE);
+        let stack_end_page =
+            Page::containing_address(HEAP_START + HEAP_MAX_SIZE + KERNEL_STACKS_SIZE - 1);
+        let stack_range = Page::range_inclusive(stack_start_page, stack_end_page);
+        stack_allocator::StackAllocator::new(stack_range)
+    };
+
+    let temporary_page =
+        paging::TemporaryPage::new(Page::containing_address(TEMPORARY_PAGE), &mut frame_allocator);
+
+    *MEMORY_CONTROLLER.lock() = Some(MemoryController {
+        active_table: active_table,
+        frame_allocator: frame_allocator,
+        stack_allocator: stack_allocator,
+        temporary_page: temporary_page,
+    });
 }
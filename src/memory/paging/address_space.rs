@@ -0,0 +1,397 @@
+use super::entry::EntryFlags;
+use super::{InactivePageTable, Mapper, Page, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
+use super::{RECURSIVE_INDEX, USER_P4_END, USER_P4_START};
+use core::cmp;
+use core::ptr;
+use interrupts;
+use memory::{self, BitmapFrameAllocator, Frame, FrameAllocator, MemoryController, PAGE_SIZE};
+use memory::{SCRATCH_PAGE_A, SCRATCH_PAGE_B};
+use usermode::{USER_SPACE_END, USER_SPACE_START};
+
+/// The page table of a user program. Its user space (P4 entries 1 to 255)
+/// belongs to it, the frames mapped there are freed on drop. The kernel
+/// entries point to the same tables as in the kernel's P4 table.
+pub struct AddressSpace {
+    table: InactivePageTable,
+}
+
+impl AddressSpace {
+    /// Creates an empty address space. The kernel entries are copied from the
+    /// active P4 table, so kernel mappings in P4 entries that are created
+    /// later aren't visible in it. Returns `None` if there is no free frame.
+    pub fn new() -> Option<AddressSpace> {
+        memory::with_memory_controller(|controller| {
+            let &mut MemoryController {
+                ref mut active_table,
+                ref mut frame_allocator,
+                ref mut temporary_page,
+                ..
+            } = controller;
+
+            let frame = frame_allocator.allocate_frame()?;
+            {
+                let table = temporary_page.map_table_frame(frame.clone(), active_table);
+                table.zero();
+                let kernel_entries = (0..USER_P4_START).chain(USER_P4_END..RECURSIVE_INDEX);
+                for index in kernel_entries {
+                    let entry = &active_table.p4()[index];
+                    if let Some(shared) = entry.pointed_frame() {
+                        table[index].set(shared, entry.flags());
+                    }
+                }
+                table[RECURSIVE_INDEX].set(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            }
+            temporary_page.unmap(active_table);
+
+            Some(AddressSpace {
+                table: InactivePageTable { p4_frame: frame },
+            })
+        })
+    }
+
+    /// The physical address of the P4 table, see `memory::switch_page_table`.
+    pub fn page_table_address(&self) -> PhysicalAddress {
+        self.table.p4_frame.start_address()
+    }
+
+    /// Maps `size` bytes at `start` to zeroed frames. `start` must be in user
+    /// space, `USER_ACCESSIBLE` is added to `flags`. Returns `false` if the
+    /// frames ran out, the pages mapped so far are freed on drop.
+    pub fn map_region(&mut self, start: VirtualAddress, size: usize, flags: EntryFlags) -> bool {
+        let (start_page, end_page) = user_pages(start, size);
+        self.with(|mapper, allocator| {
+            for page in Page::range_inclusive(start_page, end_page) {
+                let frame = match allocator.allocate_frame() {
+                    Some(frame) => frame,
+                    None => return false,
+                };
+                let bytes = match unsafe { map_scratch(mapper, allocator, &frame, SCRATCH_PAGE_A) } {
+                    Some(bytes) => bytes,
+                    None => {
+                        allocator.deallocate_frame(frame);
+                        return false;
+                    }
+                };
+                unsafe { ptr::write_bytes(bytes, 0, PAGE_SIZE) };
+                unmap_scratch(mapper, allocator, SCRATCH_PAGE_A);
+                let flags = flags | EntryFlags::USER_ACCESSIBLE;
+                if let Err(frame) = mapper.try_map_to(page, frame, flags, allocator) {
+                    allocator.deallocate_frame(frame);
+                    return false;
+                }
+            }
+            true
+        })
+    }
+
+    /// Copies `bytes` to `address`, which must be mapped. Read-only pages are
+    /// written as well. Returns `false` if there was no frame for the page
+    /// table of the scratch page, the bytes copied so far stay.
+    pub fn copy_to(&mut self, address: VirtualAddress, bytes: &[u8]) -> bool {
+        self.with(|mapper, allocator| {
+            let mut copied = 0;
+            while copied < bytes.len() {
+                let current = address + copied;
+                let offset = current % PAGE_SIZE;
+                let count = cmp::min(PAGE_SIZE - offset, bytes.len() - copied);
+                let frame = mapper
+                    .translate_page(Page::containing_address(current))
+                    .expect("copy to an unmapped page");
+                let page = match unsafe { map_scratch(mapper, allocator, &frame, SCRATCH_PAGE_A) } {
+                    Some(page) => page,
+                    None => return false,
+                };
+                unsafe {
+                    ptr::copy_nonoverlapping(bytes[copied..].as_ptr(), page.offset(offset as isize), count);
+                }
+                unmap_scratch(mapper, allocator, SCRATCH_PAGE_A);
+                copied += count;
+            }
+            true
+        })
+    }
+
+    /// Copies the mapped bytes at `address` to `bytes`, for checking what
+    /// `copy_to` wrote. Returns `false` if there was no frame for the page
+    /// table of the scratch page.
+    #[cfg(feature = "kernel-test")]
+    pub fn copy_from(&mut self, address: VirtualAddress, bytes: &mut [u8]) -> bool {
+        self.with(|mapper, allocator| {
+            let mut copied = 0;
+            while copied < bytes.len() {
+                let current = address + copied;
+                let offset = current % PAGE_SIZE;
+                let count = cmp::min(PAGE_SIZE - offset, bytes.len() - copied);
+                let frame = mapper
+                    .translate_page(Page::containing_address(current))
+                    .expect("copy from an unmapped page");
+                let page = match unsafe { map_scratch(mapper, allocator, &frame, SCRATCH_PAGE_A) } {
+                    Some(page) => page,
+                    None => return false,
+                };
+                unsafe {
+                    ptr::copy_nonoverlapping(page.offset(offset as isize), bytes[copied..].as_mut_ptr(), count);
+                }
+                unmap_scratch(mapper, allocator, SCRATCH_PAGE_A);
+                copied += count;
+            }
+            true
+        })
+    }
+
+    /// Translates `address` with this page table.
+    pub fn translate(&mut self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        self.with(|mapper, _| mapper.translate(address))
+    }
+
+    /// Returns `true` if no page of the `size` bytes at `start` is mapped.
+    /// `start` must be in user space.
+    pub fn is_unmapped(&mut self, start: VirtualAddress, size: usize) -> bool {
+        let (start_page, end_page) = user_pages(start, size);
+        self.with(|mapper, _| match next_mapped_page(mapper, start_page) {
+            Some((page, _, _)) => page > end_page,
+            None => true,
+        })
+    }
+
+    /// Creates an address space with copies of all user pages, e.g. for fork.
+    /// Returns `None` if the frames ran out.
+    pub fn try_clone(&mut self) -> Option<AddressSpace> {
+        let mut clone = AddressSpace::new()?;
+        let mut from = Page::containing_address(USER_SPACE_START);
+        loop {
+            // copy the next mapped page to a new frame
+            let copy = self.with(|mapper, allocator| {
+                next_mapped_page(mapper, from).map(|(page, frame, flags)| {
+                    let copy = allocator.allocate_frame();
+                    let copy = match copy {
+                        Some(copy) => {
+                            if copy_frame(mapper, allocator, &frame, &copy) {
+                                Some(copy)
+                            } else {
+                                allocator.deallocate_frame(copy);
+                                None
+                            }
+                        }
+                        None => None,
+                    };
+                    (page, copy, flags)
+                })
+            });
+            match copy {
+                Some((page, Some(frame), flags)) => {
+                    let mapped = clone.with(|mapper, allocator| {
+                        match mapper.try_map_to(page, frame, flags, allocator) {
+                            Ok(()) => true,
+                            Err(frame) => {
+                                allocator.deallocate_frame(frame);
+                                false
+                            }
+                        }
+                    });
+                    if !mapped {
+                        return None;
+                    }
+                    from = Page {
+                        number: page.number + 1,
+                    };
+                }
+                // out of frames, dropping the clone frees its copies
+                Some((_, None, _)) => return None,
+                None => return Some(clone),
+            }
+        }
+    }
+
+    /// Runs `f` with this page table as the target of the recursive mapping.
+    /// Interrupts stay disabled meanwhile, since `memory::translate` and the
+    /// other helpers that don't lock the memory controller read the active
+    /// table through the recursive mapping, e.g. in exception handlers.
+    fn with<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut Mapper, &mut BitmapFrameAllocator) -> R,
+    {
+        interrupts::without_interrupts(|| {
+            memory::with_memory_controller(|controller| {
+                let &mut MemoryController {
+                    ref mut active_table,
+                    ref mut frame_allocator,
+                    ref mut temporary_page,
+                    ..
+                } = controller;
+
+                let mut result = None;
+                active_table.with(&mut self.table, temporary_page, |mapper| {
+                    result = Some(f(mapper, frame_allocator));
+                });
+                result.unwrap()
+            })
+        })
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the frames mapped in user space, the user page tables and the P4
+    /// table. The shared kernel tables stay.
+    fn drop(&mut self) {
+        assert!(
+            memory::active_page_table() != self.page_table_address(),
+            "the active address space can't be dropped"
+        );
+
+        self.with(|mapper, allocator| {
+            let p4 = mapper.p4_mut();
+            for p4_index in USER_P4_START..USER_P4_END {
+                if let Some(p3) = p4.next_table_mut(p4_index) {
+                    for p3_index in 0..ENTRY_COUNT {
+                        if let Some(p2) = p3.next_table_mut(p3_index) {
+                            for p2_index in 0..ENTRY_COUNT {
+                                if let Some(p1) = p2.next_table_mut(p2_index) {
+                                    for entry in p1.iter_mut() {
+                                        if let Some(frame) = entry.pointed_frame() {
+                                            allocator.deallocate_frame(frame);
+                                        }
+                                        entry.set_unused();
+                                    }
+                                }
+                                p2.free_next_table_if_empty(p2_index, allocator);
+                            }
+                        }
+                        p3.free_next_table_if_empty(p3_index, allocator);
+                    }
+                }
+                p4.free_next_table_if_empty(p4_index, allocator);
+            }
+        });
+
+        let p4_frame = self.table.p4_frame.clone();
+        memory::with_memory_controller(|controller| {
+            controller.frame_allocator.deallocate_frame(p4_frame)
+        });
+    }
+}
+
+/// Returns the first and the last page of `size` bytes at `start`, which
+/// must lie in user space.
+fn user_pages(start: VirtualAddress, size: usize) -> (Page, Page) {
+    assert!(size > 0);
+    assert!(
+        start >= USER_SPACE_START && start + size <= USER_SPACE_END,
+        "{:#x} is not in user space",
+        start
+    );
+    (
+        Page::containing_address(start),
+        Page::containing_address(start + size - 1),
+    )
+}
+
+/// Returns the first mapped user page from `from` on, with its frame and
+/// flags.
+fn next_mapped_page(mapper: &Mapper, from: Page) -> Option<(Page, Frame, EntryFlags)> {
+    /// Returns the first page number after `number` that is a multiple of
+    /// `pages`, i.e. the start of the next table entry covering `pages`.
+    fn next_entry(number: usize, pages: usize) -> usize {
+        (number / pages + 1) * pages
+    }
+
+    let end = USER_SPACE_END / PAGE_SIZE;
+    let mut number = from.number;
+    while number < end {
+        let page = Page { number: number };
+        let p3 = match mapper.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => {
+                number = next_entry(number, ENTRY_COUNT * ENTRY_COUNT * ENTRY_COUNT);
+                continue;
+            }
+        };
+        let p2 = match p3.next_table(page.p3_index()) {
+            Some(p2) => p2,
+            None => {
+                number = next_entry(number, ENTRY_COUNT * ENTRY_COUNT);
+                continue;
+            }
+        };
+        let p1 = match p2.next_table(page.p2_index()) {
+            Some(p1) => p1,
+            None => {
+                number = next_entry(number, ENTRY_COUNT);
+                continue;
+            }
+        };
+        let entry = &p1[page.p1_index()];
+        if let Some(frame) = entry.pointed_frame() {
+            return Some((page, frame, entry.flags()));
+        }
+        number += 1;
+    }
+    None
+}
+
+/// Maps `frame` to the kernel page at `scratch` and returns a pointer to it.
+/// The scratch pages are in P4 entry 0, which all address spaces share, so
+/// the mapping is visible although `mapper` may edit an inactive table.
+/// Returns `None` if there is no frame for the page table of `scratch`.
+unsafe fn map_scratch<A>(mapper: &mut Mapper, allocator: &mut A, frame: &Frame, scratch: VirtualAddress)
+                         -> Option<*mut u8>
+where
+    A: FrameAllocator,
+{
+    let page = Page::containing_address(scratch);
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    match mapper.try_map_to(page, frame.clone(), flags, allocator) {
+        Ok(()) => Some(scratch as *mut u8),
+        Err(_) => None,
+    }
+}
+
+fn unmap_scratch<A>(mapper: &mut Mapper, allocator: &mut A, scratch: VirtualAddress)
+where
+    A: FrameAllocator,
+{
+    mapper.unmap_keep_frame(Page::containing_address(scratch), allocator);
+}
+
+/// Copies the contents of the frame `from` to the frame `to`. Returns
+/// `false` if there is no frame for the page table of the scratch pages.
+fn copy_frame<A>(mapper: &mut Mapper, allocator: &mut A, from: &Frame, to: &Frame) -> bool
+where
+    A: FrameAllocator,
+{
+    let source = match unsafe { map_scratch(mapper, allocator, from, SCRATCH_PAGE_A) } {
+        Some(source) => source,
+        None => return false,
+    };
+    // both scratch pages share a page table, which exists now
+    unsafe {
+        let destination = map_scratch(mapper, allocator, to, SCRATCH_PAGE_B).unwrap();
+        ptr::copy_nonoverlapping(source, destination, PAGE_SIZE);
+    }
+    unmap_scratch(mapper, allocator, SCRATCH_PAGE_A);
+    unmap_scratch(mapper, allocator, SCRATCH_PAGE_B);
+    true
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+
+    kernel_test!(address_space_clone_and_drop_free_all_frames {
+        let free_before = memory::frame_stats().free;
+        {
+            let mut space = AddressSpace::new().expect("no frames available");
+            assert!(space.map_region(USER_SPACE_START, 3 * PAGE_SIZE, EntryFlags::WRITABLE));
+            assert!(!space.is_unmapped(USER_SPACE_START + 2 * PAGE_SIZE, 2 * PAGE_SIZE));
+            assert!(space.is_unmapped(USER_SPACE_START + 3 * PAGE_SIZE, PAGE_SIZE));
+            assert!(space.copy_to(USER_SPACE_START + PAGE_SIZE - 2, b"spans two pages"));
+            let used_by_space = free_before - memory::frame_stats().free;
+
+            let clone = space.try_clone().expect("no frames available");
+            assert!(clone.page_table_address() != space.page_table_address());
+            // the same number of tables and page copies
+            assert_eq!(free_before - memory::frame_stats().free, 2 * used_by_space);
+        }
+        assert_eq!(memory::frame_stats().free, free_before);
+    });
+}
@@ -1,8 +1,15 @@
+use core::fmt;
 use memory::Frame;
 use multiboot2::*;
 
 pub struct Entry(u64);
 
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x} {:?}", self.0, self.flags())
+    }
+}
+
 impl Entry {
     pub fn is_unused(&self) -> bool {
         self.0 == 0
@@ -48,6 +55,24 @@ impl EntryFlags {
 
         flags
     }
+
+    /// Converts the `p_flags` of an ELF program header. The pages are always
+    /// readable.
+    pub fn from_elf_program_flags(program_flags: u32) -> EntryFlags {
+        const PF_X: u32 = 1 << 0;
+        const PF_W: u32 = 1 << 1;
+
+        let mut flags = EntryFlags::PRESENT;
+
+        if program_flags & PF_W != 0 {
+            flags = flags | EntryFlags::WRITABLE;
+        }
+        if program_flags & PF_X == 0 {
+            flags = flags | EntryFlags::NO_EXECUTE;
+        }
+
+        flags
+    }
 }
 
 bitflags! {
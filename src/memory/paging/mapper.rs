@@ -1,8 +1,9 @@
 use super::entry::*;
-use super::table::{self, Level4, Table};
-use super::{Page, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
+use super::table::{self, Level1, Level4, Table};
+use super::{supports_huge_1g, Page, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
+use super::{USER_P4_END, USER_P4_START};
 use core::ptr::Unique;
-use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use memory::{Frame, FrameAllocator, PAGE_SIZE, SPLIT_PAGE};
 
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
@@ -72,6 +73,27 @@ impl Mapper {
             .or_else(huge_page)
     }
 
+    /// Returns the P4, P3, P2 and P1 entries used to translate the given
+    /// address. The walk stops at the first entry that doesn't point to a
+    /// next level table (not present or huge page).
+    pub fn walk(&self, virtual_address: VirtualAddress) -> [Option<&Entry>; 4] {
+        let page = Page::containing_address(virtual_address);
+        let mut entries = [None; 4];
+
+        let p4 = self.p4();
+        entries[0] = Some(&p4[page.p4_index()]);
+        if let Some(p3) = p4.next_table(page.p4_index()) {
+            entries[1] = Some(&p3[page.p3_index()]);
+            if let Some(p2) = p3.next_table(page.p3_index()) {
+                entries[2] = Some(&p2[page.p2_index()]);
+                if let Some(p1) = p2.next_table(page.p2_index()) {
+                    entries[3] = Some(&p1[page.p1_index()]);
+                }
+            }
+        }
+        entries
+    }
+
     /// Maps the page to the frame with the provided flags.
     /// The `PRESENT` flag is added by default. Needs a
     /// `FrameAllocator` as it might need to create a new page table.
@@ -79,13 +101,80 @@ impl Mapper {
     where
         A: FrameAllocator,
     {
+        if self.try_map_to(page, frame, flags, allocator).is_err() {
+            panic!("no frames available");
+        }
+    }
+
+    /// Like `map_to`, but gives `frame` back if a page table is missing and
+    /// there is no free frame for it. Tables created before that stay and
+    /// are empty.
+    pub fn try_map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+                         -> Result<(), Frame>
+    where
+        A: FrameAllocator,
+    {
+        let table_flags = flags & EntryFlags::USER_ACCESSIBLE;
         let p4 = self.p4_mut();
-        let p3 = p4.next_table_create(page.p4_index(), allocator);
-        let p2 = p3.next_table_create(page.p3_index(), allocator);
-        let p1 = p2.next_table_create(page.p2_index(), allocator);
+        let p3 = match p4.try_next_table_create(page.p4_index(), table_flags, allocator) {
+            Some(p3) => p3,
+            None => return Err(frame),
+        };
+        let p2 = match p3.try_next_table_create(page.p3_index(), table_flags, allocator) {
+            Some(p2) => p2,
+            None => return Err(frame),
+        };
+        let p1 = match p2.try_next_table_create(page.p2_index(), table_flags, allocator) {
+            Some(p1) => p1,
+            None => return Err(frame),
+        };
 
         assert!(p1[page.p1_index()].is_unused());
         p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+        Ok(())
+    }
+
+    /// Maps the 2MiB region starting at `page` to the 2MiB region starting at
+    /// `frame` using a single huge P2 entry. Both must be 2MiB aligned.
+    pub fn map_to_huge_2m<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        assert!(page.p1_index() == 0, "page must be 2MiB aligned");
+        assert!(frame.number % ENTRY_COUNT == 0, "frame must be 2MiB aligned");
+
+        let table_flags = flags & EntryFlags::USER_ACCESSIBLE;
+        let p4 = self.p4_mut();
+        let p3 = p4.next_table_create(page.p4_index(), table_flags, allocator);
+        let p2 = p3.next_table_create(page.p3_index(), table_flags, allocator);
+
+        assert!(p2[page.p2_index()].is_unused());
+        p2[page.p2_index()].set(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+    }
+
+    /// Maps the 1GiB region starting at `page` to the 1GiB region starting at
+    /// `frame` using a single huge P3 entry. Both must be 1GiB aligned and the
+    /// CPU must support 1GiB pages (see `supports_huge_1g`).
+    pub fn map_to_huge_1g<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        assert!(supports_huge_1g(), "CPU does not support 1GiB pages");
+        assert!(
+            page.p2_index() == 0 && page.p1_index() == 0,
+            "page must be 1GiB aligned"
+        );
+        assert!(
+            frame.number % (ENTRY_COUNT * ENTRY_COUNT) == 0,
+            "frame must be 1GiB aligned"
+        );
+
+        let table_flags = flags & EntryFlags::USER_ACCESSIBLE;
+        let p4 = self.p4_mut();
+        let p3 = p4.next_table_create(page.p4_index(), table_flags, allocator);
+
+        assert!(p3[page.p3_index()].is_unused());
+        p3[page.p3_index()].set(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
     }
 
     /// Maps the page to some free frame with the provided flags.
@@ -111,7 +200,118 @@ impl Mapper {
 
     /// Unmaps the given page and adds all freed frames to the given
     /// `FrameAllocator`.
-    pub fn unmap<A>(&mut self, page: Page, _allocator: &mut A)
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let frame = self.unmap_keep_frame(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+
+    /// Unmaps the given page and returns the frame it was mapped to. Unlike
+    /// `unmap`, the frame is not given back to the allocator, because it is
+    /// still in use elsewhere (e.g. a page table mapped by `TemporaryPage`).
+    /// Page tables that become empty are freed through `allocator`. If the
+    /// page is part of a huge page, the huge page is split first.
+    pub fn unmap_keep_frame<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtAddr;
+
+        assert!(self.translate(page.start_address()).is_some());
+        self.split_huge_pages(page, allocator);
+
+        let frame = {
+            let p1 = self
+                .p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                .unwrap();
+            let frame = p1[page.p1_index()].pointed_frame().unwrap();
+            p1[page.p1_index()].set_unused();
+            frame
+        };
+        tlb::flush(VirtAddr::new(page.start_address() as u64));
+
+        self.free_empty_tables(page, allocator);
+        frame
+    }
+
+    /// Unmaps the 2MiB huge page starting at `page` and returns its first
+    /// frame. Like `unmap_keep_frame`, the frames aren't given back to the
+    /// allocator, because they may not come from it (e.g. a framebuffer).
+    /// Page tables that become empty are freed through `allocator`.
+    pub fn unmap_huge_2m<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtAddr;
+
+        assert!(page.p1_index() == 0, "page must be 2MiB aligned");
+
+        let start_frame = {
+            let p2 = self
+                .p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .expect("page is not mapped by a 2MiB page");
+            let entry = &mut p2[page.p2_index()];
+            assert!(
+                entry.flags().contains(EntryFlags::HUGE_PAGE),
+                "page is not mapped by a 2MiB page"
+            );
+            let frame = entry.pointed_frame().unwrap();
+            entry.set_unused();
+            frame
+        };
+        tlb::flush(VirtAddr::new(page.start_address() as u64));
+
+        self.free_empty_tables(page, allocator);
+        start_frame
+    }
+
+    /// Unmaps the 1GiB huge page starting at `page` and returns its first
+    /// frame, see `unmap_huge_2m`.
+    pub fn unmap_huge_1g<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtAddr;
+
+        assert!(
+            page.p2_index() == 0 && page.p1_index() == 0,
+            "page must be 1GiB aligned"
+        );
+
+        let start_frame = {
+            let p3 = self
+                .p4_mut()
+                .next_table_mut(page.p4_index())
+                .expect("page is not mapped by a 1GiB page");
+            let entry = &mut p3[page.p3_index()];
+            assert!(
+                entry.flags().contains(EntryFlags::HUGE_PAGE),
+                "page is not mapped by a 1GiB page"
+            );
+            let frame = entry.pointed_frame().unwrap();
+            entry.set_unused();
+            frame
+        };
+        tlb::flush(VirtAddr::new(page.start_address() as u64));
+
+        self.free_empty_tables(page, allocator);
+        start_frame
+    }
+
+    /// Changes the flags of the given 4KiB page. If the page is part of a
+    /// huge page, the huge page is split into 4KiB pages first, so that the
+    /// rest of the huge page keeps its flags.
+    pub fn update_flags<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
     where
         A: FrameAllocator,
     {
@@ -119,18 +319,200 @@ impl Mapper {
         use x86_64::VirtAddr;
 
         assert!(self.translate(page.start_address()).is_some());
+        self.split_huge_pages(page, allocator);
 
         let p1 = self
             .p4_mut()
             .next_table_mut(page.p4_index())
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
             .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("mapping code does not support huge pages");
-        let _frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
-        // tlb::flush(VirtAddr(page.start_address()));
-        tlb::flush(VirtAddr::from_ptr(&page.start_address()));
-        // TODO free p(1,2,3) table if empty
-        //allocator.deallocate_frame(frame);
+            .unwrap();
+        let frame = p1[page.p1_index()].pointed_frame().unwrap();
+        p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+        tlb::flush(VirtAddr::new(page.start_address() as u64));
+    }
+
+    /// Splits the 1GiB and 2MiB pages containing `page` (if any) until `page`
+    /// is mapped through a P1 table. The split pages keep their flags. Each
+    /// new table is filled before it replaces the huge page entry, so the
+    /// region stays mapped the whole time.
+    fn split_huge_pages<A>(&mut self, page: Page, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::instructions::tlb;
+
+        let (frame, flags) = {
+            let p3 = self.p4().next_table(page.p4_index()).expect("page is not mapped");
+            (p3[page.p3_index()].pointed_frame(), p3[page.p3_index()].flags())
+        };
+        if flags.contains(EntryFlags::HUGE_PAGE) {
+            // the entries of the new P2 table are 2MiB pages
+            let table_frame =
+                self.fill_split_table(frame.unwrap(), flags, ENTRY_COUNT, EntryFlags::HUGE_PAGE, allocator);
+            let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+            p3[page.p3_index()].set(table_frame, split_table_flags(flags));
+            // also drops stale translations of the recursive address of the
+            // new table, which pointed into the huge page before
+            tlb::flush_all();
+        }
+
+        let (frame, flags) = {
+            let p2 = self
+                .p4()
+                .next_table(page.p4_index())
+                .and_then(|p3| p3.next_table(page.p3_index()))
+                .expect("page is not mapped");
+            (p2[page.p2_index()].pointed_frame(), p2[page.p2_index()].flags())
+        };
+        if flags.contains(EntryFlags::HUGE_PAGE) {
+            let table_frame = self.fill_split_table(frame.unwrap(), flags, 1, EntryFlags::empty(), allocator);
+            let p2 = self
+                .p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .unwrap();
+            p2[page.p2_index()].set(table_frame, split_table_flags(flags));
+            tlb::flush_all();
+        }
+    }
+
+    /// Allocates a table whose entries map the huge page at `start_frame`
+    /// with its `flags`, `frames_per_entry` frames each, and adds
+    /// `extra_flags` to every entry. The table is filled through `SPLIT_PAGE`
+    /// while no entry points to it yet. Returns the frame of the table.
+    fn fill_split_table<A>(
+        &mut self,
+        start_frame: Frame,
+        flags: EntryFlags,
+        frames_per_entry: usize,
+        extra_flags: EntryFlags,
+        allocator: &mut A,
+    ) -> Frame
+    where
+        A: FrameAllocator,
+    {
+        let table_frame = allocator.allocate_frame().expect("no frames available");
+        let split_page = Page::containing_address(SPLIT_PAGE);
+        self.map_to(
+            split_page,
+            table_frame.clone(),
+            EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+            allocator,
+        );
+        {
+            let table = unsafe { &mut *(SPLIT_PAGE as *mut Table<Level1>) };
+            let flags = flags - EntryFlags::HUGE_PAGE;
+            for (i, entry) in table.iter_mut().enumerate() {
+                let frame = Frame {
+                    number: start_frame.number + i * frames_per_entry,
+                };
+                entry.set(frame, flags | extra_flags);
+            }
+        }
+        self.unmap_keep_frame(split_page, allocator)
     }
+
+    /// Walks up the table hierarchy of `page` and frees the P1, P2 and P3
+    /// tables that no longer contain any used entry. P3 tables of the kernel
+    /// P4 entries stay, because every `AddressSpace` has a copy of those
+    /// entries.
+    fn free_empty_tables<A>(&mut self, page: Page, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let p4_index = page.p4_index();
+        let p4 = self.p4_mut();
+        if let Some(p3) = p4.next_table_mut(p4_index) {
+            if let Some(p2) = p3.next_table_mut(page.p3_index()) {
+                p2.free_next_table_if_empty(page.p2_index(), allocator);
+            }
+            p3.free_next_table_if_empty(page.p3_index(), allocator);
+        }
+        if p4_index >= USER_P4_START && p4_index < USER_P4_END {
+            p4.free_next_table_if_empty(p4_index, allocator);
+        }
+    }
+}
+
+/// Returns the flags of a table entry that replaces a huge page with the
+/// given flags. Intermediate entries must not be more restrictive than the
+/// pages below them.
+fn split_table_flags(huge_page_flags: EntryFlags) -> EntryFlags {
+    EntryFlags::PRESENT | EntryFlags::WRITABLE | (huge_page_flags & EntryFlags::USER_ACCESSIBLE)
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use memory::paging::{EntryFlags, Page, ENTRY_COUNT};
+    use memory::{self, FrameAllocator, MemoryController, PAGE_SIZE};
+
+    /// An unused address with its own P3 entry (42 GiB)
+    const TEST_ADDRESS: usize = 42 * 512 * 512 * 4096;
+
+    kernel_test!(map_translate_unmap {
+        memory::with_memory_controller(|controller| {
+            let &mut MemoryController {
+                ref mut active_table,
+                ref mut frame_allocator,
+                ..
+            } = controller;
+            let free_before = frame_allocator.stats().free;
+            let page = Page::containing_address(TEST_ADDRESS);
+
+            assert!(active_table.translate(TEST_ADDRESS).is_none());
+            active_table.map(page, EntryFlags::WRITABLE, frame_allocator);
+            assert!(active_table.translate(TEST_ADDRESS).is_some());
+            unsafe {
+                *(TEST_ADDRESS as *mut u64) = 0xdeadbeaf;
+                assert_eq!(*(TEST_ADDRESS as *const u64), 0xdeadbeaf);
+            }
+
+            active_table.unmap(page, frame_allocator);
+            assert!(active_table.translate(TEST_ADDRESS).is_none());
+            // the frame and the new P2 and P1 tables were given back
+            assert_eq!(frame_allocator.stats().free, free_before);
+        });
+    });
+
+    kernel_test!(huge_page_is_split_on_flag_change {
+        memory::with_memory_controller(|controller| {
+            let &mut MemoryController {
+                ref mut active_table,
+                ref mut frame_allocator,
+                ..
+            } = controller;
+            let free_before = frame_allocator.stats().free;
+            let (start_frame, _) = frame_allocator
+                .allocate_contiguous(ENTRY_COUNT, ENTRY_COUNT)
+                .expect("no frames available");
+            let start_number = start_frame.number;
+            let start_page = Page::containing_address(TEST_ADDRESS);
+            let end_page = Page::containing_address(TEST_ADDRESS + ENTRY_COUNT * PAGE_SIZE - 1);
+
+            active_table.map_to_huge_2m(start_page, start_frame, EntryFlags::WRITABLE, frame_allocator);
+            let page = Page::containing_address(TEST_ADDRESS + 5 * PAGE_SIZE);
+            assert_eq!(
+                active_table.translate_page(page).map(|frame| frame.number),
+                Some(start_number + 5)
+            );
+
+            // make a single page read-only
+            active_table.update_flags(page, EntryFlags::empty(), frame_allocator);
+            assert_eq!(
+                active_table.translate_page(page).map(|frame| frame.number),
+                Some(start_number + 5)
+            );
+            let walk = active_table.walk(page.start_address());
+            assert!(!walk[2].unwrap().flags().contains(EntryFlags::HUGE_PAGE));
+            assert!(!walk[3].unwrap().flags().contains(EntryFlags::WRITABLE));
+            let neighbor = active_table.walk(page.start_address() + PAGE_SIZE);
+            assert!(neighbor[3].unwrap().flags().contains(EntryFlags::WRITABLE));
+
+            for page in Page::range_inclusive(start_page, end_page) {
+                active_table.unmap(page, frame_allocator);
+            }
+            assert_eq!(frame_allocator.stats().free, free_before);
+        });
+    });
 }
@@ -1,10 +1,13 @@
+pub use self::address_space::AddressSpace;
 pub use self::entry::*;
 pub use self::mapper::Mapper;
-use self::temporary_page::TemporaryPage;
+pub use self::temporary_page::TemporaryPage;
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::Ordering;
 use memory::{Frame, FrameAllocator, PAGE_SIZE};
 use multiboot2::BootInformation;
 
+mod address_space;
 mod entry;
 mod mapper;
 mod table;
@@ -12,6 +15,14 @@ mod temporary_page;
 
 const ENTRY_COUNT: usize = 512;
 
+/// The P4 entries that map user space (start inclusive, end exclusive), see
+/// `AddressSpace`. The other entries are shared by all address spaces, so
+/// the P3 tables they point to are never freed.
+const USER_P4_START: usize = 1;
+const USER_P4_END: usize = 256;
+/// The P4 entry that maps the P4 table itself
+const RECURSIVE_INDEX: usize = 511;
+
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
@@ -32,7 +43,7 @@ impl Page {
         }
     }
 
-    fn start_address(&self) -> usize {
+    pub fn start_address(&self) -> usize {
         self.number * PAGE_SIZE
     }
 
@@ -57,6 +68,7 @@ impl Page {
     }
 }
 
+#[derive(Clone)]
 pub struct PageIter {
     start: Page,
     end: Page,
@@ -76,6 +88,37 @@ impl Iterator for PageIter {
     }
 }
 
+/// Returns `true` if the CPU supports 1GiB pages (CPUID `pdpe1gb` flag).
+pub fn supports_huge_1g() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    // the extended leaf is guaranteed to exist since `boot.asm` checks for
+    // long mode support through it
+    let result = unsafe { __cpuid(0x8000_0001) };
+    result.edx & (1 << 26) != 0
+}
+
+/// Returns the physical address of the active P4 table.
+pub fn active_page_table() -> PhysicalAddress {
+    use x86_64::registers::control::Cr3;
+
+    Cr3::read().0.start_address().as_u64() as usize
+}
+
+/// Loads the P4 table at `p4_address` unless it is active already. Unlike
+/// `ActivePageTable::switch`, this doesn't need the active table, so the
+/// scheduler can use it. All P4 tables must share the kernel entries.
+pub fn switch_page_table(p4_address: PhysicalAddress) {
+    use x86_64::registers::control::{Cr3, Cr3Flags};
+    use x86_64::structures::paging::PhysFrame;
+    use x86_64::PhysAddr;
+
+    if active_page_table() != p4_address {
+        let frame = PhysFrame::containing_address(PhysAddr::new(p4_address as u64));
+        unsafe { Cr3::write(frame, Cr3Flags::empty()) };
+    }
+}
+
 pub struct ActivePageTable {
     mapper: Mapper,
 }
@@ -237,7 +280,10 @@ where
     println!("NEW TABLE");
 
     let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
-    active_table.unmap(old_p4_page, allocator);
+    // the frame is in the kernel's `.bss`, which the allocator doesn't own
+    active_table.unmap_keep_frame(old_p4_page, allocator);
+    super::register_guard_page(old_p4_page.start_address(), "boot stack (old P4 table)");
+    super::BOOT_STACK_GUARD_PAGE.store(old_p4_page.start_address(), Ordering::SeqCst);
     println!("guard page at {:#x}", old_p4_page.start_address());
 
     active_table
@@ -20,6 +20,16 @@ where
             entry.set_unused();
         }
     }
+
+    /// Returns an iterator over the entries of this table.
+    pub fn iter_mut(&mut self) -> ::core::slice::IterMut<Entry> {
+        self.entries.iter_mut()
+    }
+
+    /// Returns `true` if no entry of this table is in use.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl<L> Table<L>
@@ -47,24 +57,79 @@ where
             .map(|address| unsafe { &mut *(address as *mut _) })
     }
 
+    /// Returns the next level table at `index` and creates it if needed.
+    /// `extra_flags` (e.g. `USER_ACCESSIBLE`) is added to the entry, also if
+    /// the table exists already, since the entry must not be more restrictive
+    /// than the pages below it.
     pub fn next_table_create<A>(
         &mut self,
         index: usize,
+        extra_flags: EntryFlags,
         allocator: &mut A,
     ) -> &mut Table<L::NextLevel>
+    where
+        A: FrameAllocator,
+    {
+        self.try_next_table_create(index, extra_flags, allocator)
+            .expect("no frames available")
+    }
+
+    /// Like `next_table_create`, but returns `None` if the table has to be
+    /// created and the allocator has no free frame.
+    pub fn try_next_table_create<A>(
+        &mut self,
+        index: usize,
+        extra_flags: EntryFlags,
+        allocator: &mut A,
+    ) -> Option<&mut Table<L::NextLevel>>
     where
         A: FrameAllocator,
     {
         if self.next_table(index).is_none() {
             assert!(
                 !self.entries[index].flags().contains(EntryFlags::HUGE_PAGE),
-                "mapping code does not support huge pages"
+                "page is already mapped by a huge page"
             );
-            let frame = allocator.allocate_frame().expect("no frames available");
-            self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            let frame = allocator.allocate_frame()?;
+            self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE | extra_flags);
             self.next_table_mut(index).unwrap().zero();
+        } else if !self.entries[index].flags().contains(extra_flags) {
+            let frame = self.entries[index].pointed_frame().unwrap();
+            let flags = self.entries[index].flags() | extra_flags;
+            self.entries[index].set(frame, flags);
         }
-        self.next_table_mut(index).unwrap()
+        self.next_table_mut(index)
+    }
+
+    /// Frees the next level table at `index` if none of its entries is used
+    /// anymore. The entry is cleared and the frame of the table is given back
+    /// to the allocator. Returns `true` if the table was freed.
+    ///
+    /// Only the TLB of the current CPU is flushed. That is enough as long as
+    /// the APs only run their interrupt handlers on memory that is never
+    /// unmapped (their stacks and the kernel image), since they have no
+    /// other way to reach the freed table.
+    pub fn free_next_table_if_empty<A>(&mut self, index: usize, allocator: &mut A) -> bool
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtAddr;
+
+        let table_address = match self.next_table_address(index) {
+            Some(address) => address,
+            None => return false,
+        };
+        if !self.next_table(index).unwrap().is_empty() {
+            return false;
+        }
+
+        let frame = self[index].pointed_frame().unwrap();
+        self[index].set_unused();
+        // the table was accessible through the recursive mapping
+        tlb::flush(VirtAddr::new(table_address as u64));
+        allocator.deallocate_frame(frame);
+        true
     }
 }
 
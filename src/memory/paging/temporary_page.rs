@@ -32,9 +32,10 @@ impl TemporaryPage {
         self.page.start_address()
     }
 
-    /// Unmaps the temporary page in the active table.
+    /// Unmaps the temporary page in the active table. The mapped frame is
+    /// still owned by the caller, so it is not deallocated.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        active_table.unmap_keep_frame(self.page, &mut self.allocator);
     }
 
     /// Maps the temporary page to the given page table frame in the active
@@ -80,4 +81,40 @@ impl FrameAllocator for TinyAllocator {
         }
         panic!("Tiny allocator can hold only 3 frames.");
     }
+
+    fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<(Frame, Frame)> {
+        assert!(count > 0, "can't allocate an empty frame range");
+        assert!(align.is_power_of_two(), "`align` must be a power of 2");
+        // look for a held frame that is properly aligned and followed by
+        // `count - 1` other held frames
+        let start = self
+            .0
+            .iter()
+            .filter_map(|frame_option| frame_option.as_ref())
+            .map(|frame| frame.number)
+            .filter(|number| number & (align - 1) == 0)
+            .find(|&start| {
+                (start..start + count).all(|number| {
+                    self.0
+                        .iter()
+                        .any(|frame_option| frame_option.as_ref().map(|f| f.number) == Some(number))
+                })
+            })?;
+
+        for frame_option in &mut self.0 {
+            let taken = match *frame_option {
+                Some(ref frame) => start <= frame.number && frame.number < start + count,
+                None => false,
+            };
+            if taken {
+                frame_option.take();
+            }
+        }
+        Some((
+            Frame { number: start },
+            Frame {
+                number: start + count - 1,
+            },
+        ))
+    }
 }
@@ -0,0 +1,168 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
+use core::mem;
+use core::ptr;
+use memory::heap_allocator::GrowableHeap;
+use memory::PAGE_SIZE;
+use spin::Mutex;
+
+/// The block sizes of the slab classes. Larger allocations go to the
+/// fallback heap.
+const SIZE_CLASSES: [usize; CLASS_COUNT] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+const CLASS_COUNT: usize = 9;
+
+/// Size of the chunk that is taken from the fallback heap to refill a class.
+const SLAB_SIZE: usize = PAGE_SIZE;
+
+/// A free block, linked to the next free block of the same class.
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Allocation statistics of a single size class.
+#[derive(Debug, Clone, Copy)]
+pub struct SizeClassStats {
+    /// Block size of the class in bytes
+    pub size: usize,
+    /// Number of allocations served by this class
+    pub allocations: usize,
+    /// Number of blocks given back to this class
+    pub deallocations: usize,
+    /// Number of blocks that are ready to be handed out
+    pub free_blocks: usize,
+}
+
+#[derive(Clone, Copy)]
+struct ClassCounters {
+    allocations: usize,
+    deallocations: usize,
+    free_blocks: usize,
+}
+
+const EMPTY_COUNTERS: ClassCounters = ClassCounters {
+    allocations: 0,
+    deallocations: 0,
+    free_blocks: 0,
+};
+
+/// A fixed-size-block allocator. Small allocations are served from per-class
+/// free lists in O(1), everything else goes to a `GrowableHeap`.
+pub struct SlabAllocator {
+    classes: Mutex<SlabClasses>,
+    fallback: GrowableHeap,
+}
+
+struct SlabClasses {
+    free_lists: [Option<&'static mut ListNode>; CLASS_COUNT],
+    counters: [ClassCounters; CLASS_COUNT],
+}
+
+impl SlabAllocator {
+    /// Creates an empty allocator. `init` must be called before it is used.
+    pub const fn empty() -> Self {
+        SlabAllocator {
+            classes: Mutex::new(SlabClasses {
+                free_lists: [None, None, None, None, None, None, None, None, None],
+                counters: [EMPTY_COUNTERS; CLASS_COUNT],
+            }),
+            fallback: GrowableHeap::empty(),
+        }
+    }
+
+    /// Initializes the fallback heap, see `GrowableHeap::init`.
+    pub unsafe fn init(&self, heap_start: usize, initial_size: usize, max_size: usize) {
+        self.fallback.init(heap_start, initial_size, max_size);
+    }
+
+    /// Returns the number of bytes that are currently mapped for the heap.
+    pub fn size(&self) -> usize {
+        self.fallback.size()
+    }
+
+    /// Returns the statistics of every size class.
+    pub fn stats(&self) -> [SizeClassStats; CLASS_COUNT] {
+        let classes = self.classes.lock();
+        let mut stats = [SizeClassStats {
+            size: 0,
+            allocations: 0,
+            deallocations: 0,
+            free_blocks: 0,
+        }; CLASS_COUNT];
+        for (index, stat) in stats.iter_mut().enumerate() {
+            let counters = &classes.counters[index];
+            stat.size = SIZE_CLASSES[index];
+            stat.allocations = counters.allocations;
+            stat.deallocations = counters.deallocations;
+            stat.free_blocks = counters.free_blocks;
+        }
+        stats
+    }
+
+    /// Takes a new slab from the fallback heap and splits it into free blocks
+    /// of the given class. Returns `false` if the fallback heap is exhausted.
+    unsafe fn refill(&self, classes: &mut SlabClasses, index: usize) -> bool {
+        let block_size = SIZE_CLASSES[index];
+        // aligning the slab to the block size aligns every block in it
+        let layout = Layout::from_size_align(SLAB_SIZE, block_size).unwrap();
+        let slab = self.fallback.alloc(layout);
+        if slab.is_null() {
+            return false;
+        }
+
+        for offset in (0..SLAB_SIZE / block_size).map(|i| i * block_size) {
+            let node_ptr = slab.offset(offset as isize) as *mut ListNode;
+            node_ptr.write(ListNode {
+                next: classes.free_lists[index].take(),
+            });
+            classes.free_lists[index] = Some(&mut *node_ptr);
+        }
+        classes.counters[index].free_blocks += SLAB_SIZE / block_size;
+        true
+    }
+}
+
+/// Returns the index of the smallest size class that fits `layout`.
+fn class_index(layout: &Layout) -> Option<usize> {
+    // a block must be able to hold a `ListNode` once it is freed
+    let required = cmp::max(
+        cmp::max(layout.size(), layout.align()),
+        mem::size_of::<ListNode>(),
+    );
+    SIZE_CLASSES.iter().position(|&size| size >= required)
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let index = match class_index(&layout) {
+            Some(index) => index,
+            None => return self.fallback.alloc(layout),
+        };
+
+        let mut classes = self.classes.lock();
+        if classes.free_lists[index].is_none() && !self.refill(&mut classes, index) {
+            return ptr::null_mut();
+        }
+
+        let node = classes.free_lists[index].take().unwrap();
+        classes.free_lists[index] = node.next.take();
+        classes.counters[index].allocations += 1;
+        classes.counters[index].free_blocks -= 1;
+        node as *mut ListNode as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let index = match class_index(&layout) {
+            Some(index) => index,
+            None => return self.fallback.dealloc(ptr, layout),
+        };
+
+        let mut classes = self.classes.lock();
+        let node_ptr = ptr as *mut ListNode;
+        node_ptr.write(ListNode {
+            next: classes.free_lists[index].take(),
+        });
+        classes.free_lists[index] = Some(&mut *node_ptr);
+        classes.counters[index].deallocations += 1;
+        classes.counters[index].free_blocks += 1;
+    }
+}
@@ -0,0 +1,112 @@
+use memory::paging::{self, ActivePageTable, Page, PageIter};
+use memory::{FrameAllocator, PAGE_SIZE};
+
+/// Hands out kernel stacks from a virtual range. Every stack gets an unmapped
+/// guard page below it, so that an overflow causes a page fault instead of
+/// silently overwriting other memory.
+pub struct StackAllocator {
+    range: PageIter,
+}
+
+impl StackAllocator {
+    pub fn new(page_range: PageIter) -> StackAllocator {
+        StackAllocator { range: page_range }
+    }
+
+    /// Maps a stack of `size_in_pages` pages below a guard page. Returns
+    /// `None` if the stack range is exhausted.
+    pub fn alloc_stack<A>(
+        &mut self,
+        active_table: &mut ActivePageTable,
+        frame_allocator: &mut A,
+        size_in_pages: usize,
+    ) -> Option<Stack>
+    where
+        A: FrameAllocator,
+    {
+        if size_in_pages == 0 {
+            return None;
+        }
+
+        // clone the range, since we only want to change it on success
+        let mut range = self.range.clone();
+
+        // try to allocate the stack pages and a guard page
+        let guard_page = range.next();
+        let stack_start = range.next();
+        let stack_end = if size_in_pages == 1 {
+            stack_start
+        } else {
+            // choose the (size_in_pages-2)th element, since index
+            // starts at 0 and we already allocated the start page
+            range.nth(size_in_pages - 2)
+        };
+
+        match (guard_page, stack_start, stack_end) {
+            (Some(guard_page), Some(start), Some(end)) => {
+                // success! write back updated range
+                self.range = range;
+
+                // map stack pages to physical frames
+                for page in Page::range_inclusive(start, end) {
+                    active_table.map(
+                        page,
+                        paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE,
+                        frame_allocator,
+                    );
+                }
+                super::register_guard_page(guard_page.start_address(), "a kernel stack");
+
+                // create a new stack
+                let top_of_stack = end.start_address() + PAGE_SIZE;
+                Some(Stack::new(top_of_stack, start.start_address()))
+            }
+            _ => None, /* not enough pages */
+        }
+    }
+
+    /// Unmaps the pages of the stack and frees their frames. The virtual
+    /// range of the stack is not handed out again.
+    pub fn dealloc_stack<A>(
+        &mut self,
+        stack: Stack,
+        active_table: &mut ActivePageTable,
+        frame_allocator: &mut A,
+    ) where
+        A: FrameAllocator,
+    {
+        let start = Page::containing_address(stack.bottom);
+        let end = Page::containing_address(stack.top - 1);
+        for page in Page::range_inclusive(start, end) {
+            active_table.unmap(page, frame_allocator);
+        }
+        super::unregister_guard_page(stack.bottom - PAGE_SIZE);
+    }
+}
+
+/// A mapped kernel stack. The page below `bottom` is an unmapped guard page.
+#[derive(Debug)]
+pub struct Stack {
+    top: usize,
+    bottom: usize,
+}
+
+impl Stack {
+    fn new(top: usize, bottom: usize) -> Stack {
+        assert!(top > bottom);
+        Stack {
+            top: top,
+            bottom: bottom,
+        }
+    }
+
+    /// The address right above the stack, the initial stack pointer.
+    pub fn top(&self) -> usize {
+        self.top
+    }
+
+    /// The lowest address of the stack.
+    pub fn bottom(&self) -> usize {
+        self.bottom
+    }
+}
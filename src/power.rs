@@ -0,0 +1,105 @@
+use acpi::{self, ADDRESS_SPACE_IO, ADDRESS_SPACE_MEMORY};
+use cpuio::Port;
+use memory::{self, EntryFlags};
+use x86_64;
+
+/// PM1 control register: SCI interrupts are enabled, i.e. ACPI mode is on
+const SCI_ENABLE: u16 = 1 << 0;
+/// PM1 control register: enter the sleep state of `SLP_TYP`
+const SLEEP_ENABLE: u16 = 1 << 13;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+
+/// 8042 status register: the input buffer is full
+const KBC_INPUT_FULL: u8 = 1 << 1;
+/// 8042 command that pulses the CPU reset line
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+/// Number of times ports are polled before giving up
+const POLL_LIMIT: usize = 100_000;
+
+/// Powers the machine off by entering the ACPI soft off state (`\_S5`).
+/// Halts forever if that isn't possible.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    if let Some(acpi) = acpi::tables() {
+        let fadt = acpi.fadt.as_ref().filter(|fadt| fadt.pm1a_control_block != 0);
+        if let (Some(fadt), Some(sleep_type)) = (fadt, acpi.s5_sleep_type) {
+            unsafe {
+                let mut pm1a = Port::<u16>::new(fadt.pm1a_control_block as u16);
+                if pm1a.read() & SCI_ENABLE == 0 && fadt.smi_command != 0 {
+                    // ask the firmware to switch to ACPI mode
+                    Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable);
+                    for _ in 0..POLL_LIMIT {
+                        if pm1a.read() & SCI_ENABLE != 0 {
+                            break;
+                        }
+                    }
+                }
+
+                let value = |sleep_type: u8| {
+                    (u16::from(sleep_type) << SLEEP_TYPE_SHIFT) | SLEEP_ENABLE
+                };
+                pm1a.write(value(sleep_type.a));
+                if fadt.pm1b_control_block != 0 {
+                    Port::<u16>::new(fadt.pm1b_control_block as u16).write(value(sleep_type.b));
+                }
+            }
+        }
+    }
+
+    println!("shutdown failed, it is now safe to turn off the computer");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Reboots the machine through the ACPI reset register. Falls back to the
+/// reset line of the 8042 keyboard controller and finally to a triple fault.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let fadt = acpi::tables().and_then(|acpi| acpi.fadt.as_ref());
+    if let Some(&(register, value)) = fadt.and_then(|fadt| fadt.reset.as_ref()) {
+        match register.address_space {
+            ADDRESS_SPACE_IO => unsafe { Port::<u8>::new(register.address as u16).write(value) },
+            ADDRESS_SPACE_MEMORY => {
+                let address = register.address as usize;
+                let flags = EntryFlags::WRITABLE | EntryFlags::NO_CACHE;
+                if memory::identity_map_region(address, 1, flags) {
+                    unsafe { (address as *mut u8).write_volatile(value) }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if fadt.map(|fadt| fadt.has_8042).unwrap_or(true) {
+        unsafe {
+            let mut status = Port::<u8>::new(0x64);
+            for _ in 0..POLL_LIMIT {
+                if status.read() & KBC_INPUT_FULL == 0 {
+                    break;
+                }
+            }
+            status.write(KBC_PULSE_RESET);
+        }
+    }
+
+    triple_fault()
+}
+
+/// Loads an empty IDT and raises an exception, which the CPU can't deliver,
+/// so it resets.
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+
+    let idt = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        lidt(&idt);
+        asm!("int3" :::: "intel", "volatile");
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
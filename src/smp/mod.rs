@@ -0,0 +1,136 @@
+use acpi;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use gdt;
+use interrupts::{self, apic};
+use memory::{self, EntryFlags};
+use syscall;
+use time;
+use x86_64;
+use KERNEL_STACK_PAGES;
+
+pub mod percpu;
+
+/// Physical address the AP trampoline is copied to. It must be page aligned
+/// and below 1MiB, because the startup IPI passes its page number.
+const AP_TRAMPOLINE_BASE: usize = 0x8000;
+/// Offset of the `TrampolineData` in the trampoline
+const AP_TRAMPOLINE_DATA_OFFSET: usize = 8;
+
+extern "C" {
+    // defined in `ap_trampoline.asm`
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The data fields of the trampoline, see `ap_trampoline.asm`.
+#[repr(C)]
+struct TrampolineData {
+    /// Physical address of the P4 table
+    page_table: u64,
+    stack_top: u64,
+    /// Address of the function the AP calls in long mode
+    entry: u64,
+    /// Argument of the entry function, the index of the AP
+    argument: u64,
+}
+
+/// Number of CPUs that are running, including the bootstrap processor
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+/// Set by an AP as soon as it has left the trampoline
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the number of CPUs that are running.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Starts the enabled processors of the MADT one after another with the
+/// INIT-SIPI-SIPI sequence. Needs the APICs and a running tick source.
+/// Returns the number of CPUs that are running afterwards.
+pub fn boot_application_processors() -> usize {
+    let madt = match acpi::tables().and_then(|acpi| acpi.madt.as_ref()) {
+        Some(madt) if interrupts::apic_enabled() => madt,
+        _ => return cpu_count(),
+    };
+
+    let data = unsafe { install_trampoline() };
+    let bsp_apic_id = apic::local_apic_id();
+    for processor in madt.processors.iter().filter(|p| p.enabled && p.apic_id != bsp_apic_id) {
+        let index = cpu_count();
+        let stack = memory::alloc_stack(KERNEL_STACK_PAGES).expect("could not allocate AP stack");
+        unsafe {
+            ptr::write_volatile(&mut data.stack_top, stack.top() as u64);
+            ptr::write_volatile(&mut data.argument, index as u64);
+        }
+        AP_STARTED.store(false, Ordering::SeqCst);
+
+        let page = (AP_TRAMPOLINE_BASE / memory::PAGE_SIZE) as u8;
+        apic::send_init(processor.apic_id);
+        time::sleep(Duration::from_millis(10));
+        apic::send_startup(processor.apic_id, page);
+        // a second startup IPI is needed if the first one was missed
+        if !wait_for_ap(Duration::from_millis(1)) {
+            apic::send_startup(processor.apic_id, page);
+            if !wait_for_ap(Duration::from_millis(100)) {
+                // park the AP in the wait-for-SIPI state, so that it can't
+                // start late on the data of the next AP. Its stack is leaked
+                // and no other AP is started, in case it ran anyway.
+                apic::send_init(processor.apic_id);
+                println!("CPU with APIC ID {} did not start, stopping SMP bring-up", processor.apic_id);
+                break;
+            }
+        }
+        CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+        println!("CPU {} started (APIC ID {})", index, processor.apic_id);
+    }
+    cpu_count()
+}
+
+/// Copies the trampoline to low memory, which `memory::init` keeps free,
+/// and fills in the fields that are the same for all APs.
+unsafe fn install_trampoline() -> &'static mut TrampolineData {
+    use x86_64::registers::control::Cr3;
+
+    let start = &ap_trampoline_start as *const u8;
+    let size = &ap_trampoline_end as *const u8 as usize - start as usize;
+    // the AP executes the trampoline from here once paging is enabled
+    let mapped = memory::identity_map_region(AP_TRAMPOLINE_BASE, size, EntryFlags::WRITABLE);
+    assert!(mapped, "the AP trampoline collides with a kernel mapping");
+    ptr::copy_nonoverlapping(start, AP_TRAMPOLINE_BASE as *mut u8, size);
+
+    let data = &mut *((AP_TRAMPOLINE_BASE + AP_TRAMPOLINE_DATA_OFFSET) as *mut TrampolineData);
+    data.page_table = Cr3::read().0.start_address().as_u64();
+    data.entry = ap_main as usize as u64;
+    data
+}
+
+/// Waits until the AP reports that it started or `timeout` passed.
+fn wait_for_ap(timeout: Duration) -> bool {
+    let deadline = time::deadline_after(timeout);
+    while time::uptime() < deadline {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        x86_64::instructions::hlt();
+    }
+    AP_STARTED.load(Ordering::SeqCst)
+}
+
+/// Rust entry point of the APs, called by the trampoline on the stack
+/// allocated by `boot_application_processors`.
+extern "C" fn ap_main(index: usize) -> ! {
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    ::init_idt();
+    let tables = gdt::init();
+    apic::init_local_apic();
+    percpu::init(index, apic::local_apic_id(), tables);
+    syscall::init();
+    x86_64::instructions::interrupts::enable();
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
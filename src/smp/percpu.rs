@@ -0,0 +1,99 @@
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use gdt::CpuTables;
+use x86_64::registers::model_specific::Msr;
+
+/// The MSR that holds the base address of the GS segment
+const IA32_GS_BASE: u32 = 0xc000_0101;
+/// The MSR whose value `swapgs` exchanges with the GS base
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// Data that every CPU has its own copy of. In the kernel, the GS base of a
+/// CPU points to its block. In ring 3 the GS base belongs to the program and
+/// the block is kept in `IA32_KERNEL_GS_BASE`, so every entry from ring 3
+/// must `swapgs` first (see `KernelGs`) and swap back before returning.
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of the block itself, so that it can be read through `gs:0`
+    self_address: usize,
+    /// The stack the `syscall` entry switches to (`gs:8`), the same as RSP0
+    /// in the TSS
+    syscall_stack_top: AtomicUsize,
+    /// Scratch slot for the user stack pointer in the `syscall` entry
+    /// (`gs:16`)
+    #[allow(dead_code)]
+    user_stack_pointer: AtomicUsize,
+    /// Index of the CPU, the bootstrap processor has index 0
+    pub index: usize,
+    /// ID of the local APIC of the CPU
+    pub apic_id: u8,
+    /// The GDT and TSS of the CPU
+    pub tables: &'static CpuTables,
+}
+
+/// Creates the per-CPU block of the current CPU and points the GS base to
+/// it. The GS base of ring 3 starts as 0. Needs the heap.
+pub fn init(index: usize, apic_id: u8, tables: &'static CpuTables) {
+    let block = Box::leak(Box::new(PerCpu {
+        self_address: 0,
+        syscall_stack_top: AtomicUsize::new(tables.kernel_stack()),
+        user_stack_pointer: AtomicUsize::new(0),
+        index: index,
+        apic_id: apic_id,
+        tables: tables,
+    }));
+    block.self_address = block as *const PerCpu as usize;
+    unsafe {
+        Msr::new(IA32_GS_BASE).write(block.self_address as u64);
+        Msr::new(IA32_KERNEL_GS_BASE).write(0);
+    }
+}
+
+/// Returns the per-CPU block of the current CPU. `init` must have been
+/// called on this CPU.
+pub fn current() -> &'static PerCpu {
+    let address: usize;
+    unsafe {
+        asm!("mov $0, gs:0" : "=r"(address) ::: "intel");
+        &*(address as *const PerCpu)
+    }
+}
+
+impl PerCpu {
+    /// Sets the stack that the `syscall` entry of this CPU switches to.
+    pub fn set_syscall_stack(&self, stack_top: usize) {
+        self.syscall_stack_top.store(stack_top, Ordering::SeqCst);
+    }
+}
+
+/// Makes the GS base point to the per-CPU block for the lifetime of the
+/// guard. Interrupt and exception handlers create it before anything else.
+///
+/// Instead of checking the privilege level in the interrupt frame, it checks
+/// whether the GS base is 0. Ring 3 can't change its GS base (`FSGSBASE` is
+/// off and the data segments have base 0), so it is 0 exactly when the GS
+/// base of the program is loaded. This also covers an NMI or machine check
+/// that arrives in the kernel between the entry from ring 3 and its `swapgs`,
+/// or between the `swapgs` and the return.
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    pub fn enter() -> KernelGs {
+        let user_gs = unsafe { Msr::new(IA32_GS_BASE).read() } == 0;
+        if user_gs {
+            unsafe { asm!("swapgs" :::: "volatile") };
+        }
+        KernelGs { swapped: user_gs }
+    }
+}
+
+impl Drop for KernelGs {
+    /// Restores the GS base of the program if `enter` swapped it.
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { asm!("swapgs" :::: "volatile") };
+        }
+    }
+}
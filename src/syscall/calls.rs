@@ -0,0 +1,198 @@
+//! The implementations of the system calls in `SYSCALLS`. Pointer arguments
+//! are checked against the page tables before the kernel touches them.
+
+use core::ptr;
+use core::slice;
+use core::str;
+use core::time::Duration;
+use memory::{self, EntryFlags, PAGE_SIZE};
+use power;
+use syscall::Error;
+use task;
+use thread;
+use time;
+use usermode::{USER_SPACE_END, USER_SPACE_START};
+
+/// How often `read_key` checks for input while it waits, in milliseconds
+const KEY_POLL_INTERVAL: u64 = 10;
+
+/// The protection bits of `mmap`, the pages are always readable
+const PROT_WRITE: u64 = 1 << 0;
+const PROT_EXEC: u64 = 1 << 1;
+
+/// `exit()` ends the calling thread. Doesn't return.
+pub fn exit(_arguments: &[u64; 6]) -> Result<u64, Error> {
+    thread::exit()
+}
+
+/// `write(buffer, len)` prints `len` bytes of UTF-8 text from `buffer` to the
+/// console. Returns `len`.
+pub fn write(arguments: &[u64; 6]) -> Result<u64, Error> {
+    let (address, len) = (arguments[0], arguments[1]);
+    validate_buffer(address, len, false)?;
+    let bytes = unsafe { slice::from_raw_parts(address as *const u8, len as usize) };
+    let text = str::from_utf8(bytes).map_err(|_| Error::InvalidArgument)?;
+    print!("{}", text);
+    Ok(len)
+}
+
+/// `read_key()` waits for a typed character and returns it.
+pub fn read_key(_arguments: &[u64; 6]) -> Result<u64, Error> {
+    loop {
+        if let Some(character) = task::keyboard::read_char() {
+            return Ok(character as u64);
+        }
+        thread::sleep(Duration::from_millis(KEY_POLL_INTERVAL));
+    }
+}
+
+/// `sleep(milliseconds)` blocks the calling thread.
+pub fn sleep(arguments: &[u64; 6]) -> Result<u64, Error> {
+    thread::sleep(Duration::from_millis(arguments[0]));
+    Ok(0)
+}
+
+/// `time()` returns the seconds since 1970-01-01 00:00:00 UTC.
+pub fn time(_arguments: &[u64; 6]) -> Result<u64, Error> {
+    Ok(time::unix_timestamp())
+}
+
+/// `mmap(address, len, protection)` maps zeroed pages at the page aligned
+/// `address`. `protection` is a combination of `PROT_WRITE` and
+/// `PROT_EXEC`. Returns `address`.
+pub fn mmap(arguments: &[u64; 6]) -> Result<u64, Error> {
+    let (address, len, protection) = (arguments[0], arguments[1], arguments[2]);
+    if protection & !(PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let (start, end) = page_range(address, len)?;
+    // bounds the page table walk below, the mapping would fail anyway
+    if (end - start) / PAGE_SIZE > memory::frame_stats().free {
+        return Err(Error::OutOfMemory);
+    }
+    let mut page = start;
+    while page < end {
+        if memory::translate(page).is_some() {
+            return Err(Error::AddressInUse);
+        }
+        page += PAGE_SIZE;
+    }
+
+    // writable for zeroing, the requested flags are set afterwards
+    let initial_flags = EntryFlags::USER_ACCESSIBLE | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    if !memory::map_region(start, end - start, initial_flags) {
+        return Err(Error::OutOfMemory);
+    }
+    unsafe { ptr::write_bytes(start as *mut u8, 0, end - start) };
+
+    let mut flags = EntryFlags::USER_ACCESSIBLE;
+    if protection & PROT_WRITE != 0 {
+        flags = flags | EntryFlags::WRITABLE;
+    }
+    if protection & PROT_EXEC == 0 {
+        flags = flags | EntryFlags::NO_EXECUTE;
+    }
+    memory::set_region_flags(start, end - start, flags);
+    Ok(address)
+}
+
+/// `munmap(address, len)` unmaps the pages of `len` bytes at the page
+/// aligned `address`. All of them must be mapped.
+pub fn munmap(arguments: &[u64; 6]) -> Result<u64, Error> {
+    let (address, len) = (arguments[0], arguments[1]);
+    let (start, end) = page_range(address, len)?;
+    validate_buffer(start as u64, (end - start) as u64, false)?;
+    memory::unmap_region(start, end - start);
+    Ok(0)
+}
+
+/// `getpid()` returns the ID of the calling thread.
+pub fn getpid(_arguments: &[u64; 6]) -> Result<u64, Error> {
+    Ok(thread::current().as_usize() as u64)
+}
+
+/// `shutdown()` powers the machine off, see `power::shutdown`. Doesn't
+/// return.
+pub fn shutdown(_arguments: &[u64; 6]) -> Result<u64, Error> {
+    power::shutdown()
+}
+
+/// `reboot()` resets the machine, see `power::reboot`. Doesn't return.
+pub fn reboot(_arguments: &[u64; 6]) -> Result<u64, Error> {
+    power::reboot()
+}
+
+/// Returns the start and end of `len` bytes at `address` if they lie in
+/// user space.
+fn user_range(address: u64, len: u64) -> Result<(usize, usize), Error> {
+    let start = address as usize;
+    let end = start.checked_add(len as usize).ok_or(Error::InvalidAddress)?;
+    if start < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(Error::InvalidAddress);
+    }
+    Ok((start, end))
+}
+
+/// Like `user_range`, but `address` must be page aligned and the end is
+/// rounded up to the next page.
+fn page_range(address: u64, len: u64) -> Result<(usize, usize), Error> {
+    if len == 0 || address as usize % PAGE_SIZE != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    // larger ranges can't fit, and rounding them up could overflow
+    if len > (USER_SPACE_END - USER_SPACE_START) as u64 {
+        return Err(Error::InvalidAddress);
+    }
+    let pages = (len as usize - 1) / PAGE_SIZE + 1;
+    let size = pages.checked_mul(PAGE_SIZE).ok_or(Error::InvalidAddress)?;
+    user_range(address, size as u64)
+}
+
+/// Checks that the `len` bytes at `address` lie in user space and that every
+/// page is mapped and accessible from ring 3, and writable if `writable`.
+fn validate_buffer(address: u64, len: u64, writable: bool) -> Result<(), Error> {
+    let (start, end) = user_range(address, len)?;
+    let mut page = start & !(PAGE_SIZE - 1);
+    while page < end {
+        memory::translate(page).ok_or(Error::InvalidAddress)?;
+        let flags = memory::page_flags(page).ok_or(Error::InvalidAddress)?;
+        if !flags.contains(EntryFlags::USER_ACCESSIBLE)
+            || (writable && !flags.contains(EntryFlags::WRITABLE))
+        {
+            return Err(Error::InvalidAddress);
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+
+    /// An unused page in user space
+    const TEST_ADDRESS: usize = USER_SPACE_START + 42 * 512 * 512 * 4096;
+
+    kernel_test!(buffers_are_validated {
+        // kernel memory
+        assert_eq!(validate_buffer(0xb8000, 16, false), Err(Error::InvalidAddress));
+        // not mapped
+        assert_eq!(validate_buffer(TEST_ADDRESS as u64, 16, false), Err(Error::InvalidAddress));
+
+        let arguments = [TEST_ADDRESS as u64, 2 * PAGE_SIZE as u64, 0, 0, 0, 0];
+        let huge = [TEST_ADDRESS as u64, u64::max_value(), 0, 0, 0, 0];
+        assert_eq!(mmap(&huge), Err(Error::InvalidAddress));
+        assert_eq!(mmap(&arguments), Ok(TEST_ADDRESS as u64));
+        assert_eq!(mmap(&arguments), Err(Error::AddressInUse));
+        assert_eq!(validate_buffer(TEST_ADDRESS as u64, 16, false), Ok(()));
+        assert_eq!(validate_buffer(TEST_ADDRESS as u64, 16, true), Err(Error::InvalidAddress));
+        assert_eq!(munmap(&arguments), Ok(0));
+        assert_eq!(validate_buffer(TEST_ADDRESS as u64, 16, false), Err(Error::InvalidAddress));
+    });
+
+    kernel_test!(sleep_deadlines_saturate {
+        let forever = Duration::from_millis(u64::max_value());
+        assert_eq!(time::duration_as_nanos(forever), u64::max_value());
+        assert_eq!(time::deadline_after(forever), u64::max_value());
+    });
+}
@@ -0,0 +1,146 @@
+use core::mem;
+use gdt;
+use x86_64;
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable};
+use x86_64::PrivilegeLevel;
+
+mod calls;
+
+/// The MSRs that configure the `syscall` instruction: segments, entry point
+/// and the RFLAGS bits to clear
+const IA32_STAR: u32 = 0xc000_0081;
+const IA32_LSTAR: u32 = 0xc000_0082;
+const IA32_FMASK: u32 = 0xc000_0084;
+
+/// Cleared on `syscall`: the trap, interrupt enable and direction flags
+const SYSCALL_FLAG_MASK: u64 = 0x700;
+
+/// The interrupt for system calls from code that can't use `syscall`
+pub const SYSCALL_INTERRUPT_ID: usize = 0x80;
+
+/// System call numbers, passed in RAX
+pub const EXIT: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const READ_KEY: u64 = 2;
+pub const SLEEP: u64 = 3;
+pub const TIME: u64 = 4;
+pub const MMAP: u64 = 5;
+pub const MUNMAP: u64 = 6;
+pub const GETPID: u64 = 7;
+pub const SHUTDOWN: u64 = 8;
+pub const REBOOT: u64 = 9;
+
+/// The implementation of a system call. It gets the six argument registers
+/// and returns the value for RAX.
+type Syscall = fn(&[u64; 6]) -> Result<u64, Error>;
+
+/// The system call table
+static SYSCALLS: [(u64, Syscall); 10] = [
+    (EXIT, calls::exit),
+    (WRITE, calls::write),
+    (READ_KEY, calls::read_key),
+    (SLEEP, calls::sleep),
+    (TIME, calls::time),
+    (MMAP, calls::mmap),
+    (MUNMAP, calls::munmap),
+    (GETPID, calls::getpid),
+    (SHUTDOWN, calls::shutdown),
+    (REBOOT, calls::reboot),
+];
+
+/// Why a system call failed. It is returned negated in RAX, so results from
+/// `-4095` to `-1` (as `i64`) are errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Error {
+    /// The number in RAX is not in the system call table
+    UnknownSyscall = 1,
+    /// A pointer argument isn't mapped for the user, or not writable
+    InvalidAddress = 2,
+    /// An argument is out of range or malformed, e.g. non-UTF-8 text
+    InvalidArgument = 3,
+    /// `mmap` found a page in the requested range that is mapped already
+    AddressInUse = 4,
+    OutOfMemory = 5,
+}
+
+impl Error {
+    fn to_register(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+}
+
+extern "C" {
+    // defined in `syscall.asm`
+    fn syscall_entry();
+    fn syscall_interrupt_entry();
+}
+
+/// The registers of a system call, saved on the kernel stack by the entry
+/// code in `syscall.asm`. The number is passed in RAX and the arguments in
+/// RDI, RSI, RDX, R10, R8 and R9, the result is returned in RAX.
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+}
+
+impl Registers {
+    /// Returns the arguments in the order of the calling convention.
+    pub fn arguments(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+/// Enables the `syscall` instruction on the current CPU. Must be called on
+/// every CPU after `gdt::init` and `smp::percpu::init`.
+pub fn init() {
+    let selectors = gdt::selectors();
+    // `syscall` loads CS from STAR[47:32] and SS from the next descriptor,
+    // `sysret` loads SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16
+    assert!(selectors.kernel_data.index() == selectors.kernel_code.index() + 1);
+    assert!(selectors.user_data.index() == selectors.kernel_data.index() + 1);
+    assert!(selectors.user_code.index() == selectors.user_data.index() + 1);
+    let star = (u64::from(selectors.kernel_data.0 | 3) << 48) | (u64::from(selectors.kernel_code.0) << 32);
+
+    unsafe {
+        Msr::new(IA32_STAR).write(star);
+        Msr::new(IA32_LSTAR).write(syscall_entry as usize as u64);
+        Msr::new(IA32_FMASK).write(SYSCALL_FLAG_MASK);
+        Efer::write(Efer::read() | EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+}
+
+/// Installs the `int 0x80` gate, which ring 3 may use.
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    // the handler is written in assembly, since it needs the registers of
+    // the caller, and only has the address of a `HandlerFunc`
+    let handler: HandlerFunc =
+        unsafe { mem::transmute(syscall_interrupt_entry as unsafe extern "C" fn()) };
+    idt[SYSCALL_INTERRUPT_ID]
+        .set_handler_fn(handler)
+        .set_privilege_level(PrivilegeLevel::Ring3);
+}
+
+/// Called by both system call entries with interrupts disabled.
+#[no_mangle]
+pub extern "C" fn syscall_handler(registers: &mut Registers) {
+    // system calls may block, the entries disable interrupts again on return
+    x86_64::instructions::interrupts::enable();
+
+    let number = registers.rax;
+    let result = match SYSCALLS.iter().find(|&&(n, _)| n == number) {
+        Some(&(_, syscall)) => syscall(&registers.arguments()),
+        None => Err(Error::UnknownSyscall),
+    };
+    registers.rax = match result {
+        Ok(value) => value,
+        Err(error) => error.to_register(),
+    };
+}
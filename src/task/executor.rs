@@ -0,0 +1,168 @@
+use alloc::collections::BTreeMap;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{LocalWaker, Poll, UnsafeWake, Waker};
+use interrupts;
+use spin::Mutex;
+use task::ring_buffer::RingBuffer;
+use task::{Task, TaskId};
+use x86_64;
+
+lazy_static! {
+    /// The tasks that were woken and wait to be polled. A waker only pushes
+    /// the ID of its task here, so waking never allocates and works from
+    /// interrupt handlers. There is one queue, so there must be one
+    /// `Executor` running at a time.
+    static ref WAKE_QUEUE: Mutex<RingBuffer<TaskId>> = Mutex::new(RingBuffer::new(TaskId(0)));
+}
+
+/// Wake-ups that didn't fit into the wake queue since the last report, see
+/// `run_ready_tasks`. Counted instead of printed, because the waker may run
+/// in an interrupt handler that interrupted a `println!`.
+static DROPPED_WAKEUPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Polls the spawned tasks whenever they are woken and halts the CPU while
+/// none of them is.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            tasks: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `task`, it is polled the first time by the next `run` or
+    /// `run_ready_tasks`.
+    pub fn spawn(&mut self, task: Task) {
+        let id = task.id();
+        assert!(self.tasks.insert(id, task).is_none(), "task spawned twice");
+        wake(id);
+    }
+
+    /// Returns the number of unfinished tasks.
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Polls the woken tasks until none is left. Removes the finished ones.
+    pub fn run_ready_tasks(&mut self) {
+        let dropped = DROPPED_WAKEUPS.swap(0, Ordering::SeqCst);
+        if dropped > 0 {
+            println!("WARNING: wake queue full, dropped {} wake-ups", dropped);
+        }
+        while let Some(id) = interrupts::without_interrupts(|| WAKE_QUEUE.lock().pop()) {
+            let finished = match self.tasks.get_mut(&id) {
+                Some(task) => task.poll(&local_waker(id)) == Poll::Ready(()),
+                // woken after it finished
+                None => false,
+            };
+            if finished {
+                self.tasks.remove(&id);
+            }
+        }
+    }
+
+    /// Runs the tasks forever. Interrupts must be enabled, they wake the CPU.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Halts until the next interrupt if no task is woken. Checking the queue
+    /// and halting happens with interrupts disabled, otherwise a wake-up
+    /// right before `hlt` would be missed.
+    fn sleep_if_idle(&self) {
+        x86_64::instructions::interrupts::disable();
+        if WAKE_QUEUE.lock().is_empty() {
+            // `sti` only takes effect after `hlt`, so no interrupt slips in
+            unsafe { asm!("sti; hlt" :::: "volatile") };
+        } else {
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+}
+
+/// Queues the task `id` to be polled.
+fn wake(id: TaskId) {
+    interrupts::without_interrupts(|| {
+        if !WAKE_QUEUE.lock().push(id) {
+            DROPPED_WAKEUPS.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+}
+
+/// The waker of a task. It is never allocated: its address is the task ID
+/// plus one, which is a valid address for a zero-sized type. So creating,
+/// cloning and dropping a waker is free and works in interrupt handlers.
+struct TaskWaker;
+
+impl TaskWaker {
+    fn id(&self) -> TaskId {
+        TaskId(self as *const TaskWaker as usize - 1)
+    }
+}
+
+unsafe impl UnsafeWake for TaskWaker {
+    unsafe fn clone_raw(&self) -> Waker {
+        Waker::new(task_waker(self.id()))
+    }
+
+    unsafe fn drop_raw(&self) {}
+
+    unsafe fn wake(&self) {
+        wake(self.id());
+    }
+}
+
+fn task_waker(id: TaskId) -> NonNull<dyn UnsafeWake> {
+    unsafe { NonNull::new_unchecked((id.0 + 1) as *mut TaskWaker as *mut dyn UnsafeWake) }
+}
+
+/// Returns a waker that queues the task `id`.
+fn local_waker(id: TaskId) -> LocalWaker {
+    unsafe { LocalWaker::new(task_waker(id)) }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static POLLED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Returns `Pending` and wakes itself the first time it is polled, counts
+    /// in `POLLED` when it is done.
+    struct YieldOnce {
+        yielded: bool,
+    }
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<()> {
+            if self.yielded {
+                POLLED.fetch_add(1, Ordering::SeqCst);
+                return Poll::Ready(());
+            }
+            self.yielded = true;
+            waker.wake();
+            Poll::Pending
+        }
+    }
+
+    kernel_test!(woken_tasks_run_to_completion {
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(YieldOnce { yielded: false }));
+        executor.spawn(Task::new(YieldOnce { yielded: false }));
+        executor.run_ready_tasks();
+        assert_eq!(executor.task_count(), 0);
+        assert_eq!(POLLED.load(Ordering::SeqCst), 2);
+    });
+}
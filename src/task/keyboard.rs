@@ -0,0 +1,130 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{LocalWaker, Poll, Waker};
+use interrupts;
+use keyboard;
+use spin::Mutex;
+use task::ring_buffer::RingBuffer;
+use vga_buffer;
+
+lazy_static! {
+    /// Scancodes received by the keyboard interrupt and not consumed yet
+    static ref SCANCODES: Mutex<RingBuffer<u8>> = Mutex::new(RingBuffer::new(0));
+    /// Characters echoed by `PrintKeypresses` and not read yet, see
+    /// `read_char`
+    static ref CHARACTERS: Mutex<RingBuffer<char>> = Mutex::new(RingBuffer::new('\0'));
+}
+
+/// Woken when a scancode arrives
+static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+/// Scancodes that didn't fit into the queue since the last report, see
+/// `PrintKeypresses`
+static DROPPED_SCANCODES: AtomicUsize = AtomicUsize::new(0);
+
+/// Queues a scancode for the `ScancodeStream`. Called from the keyboard
+/// interrupt, so it must not block or allocate. It must not print either,
+/// the interrupted code may hold the lock of the screen.
+pub fn add_scancode(scancode: u8) {
+    if !SCANCODES.lock().push(scancode) {
+        DROPPED_SCANCODES.fetch_add(1, Ordering::SeqCst);
+        return;
+    }
+    if let Some(ref waker) = *WAKER.lock() {
+        waker.wake();
+    }
+}
+
+/// The scancodes of the keyboard. There is only one waker slot, so only one
+/// stream can exist.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> ScancodeStream {
+        static CREATED: AtomicBool = AtomicBool::new(false);
+        assert!(!CREATED.swap(true, Ordering::SeqCst), "ScancodeStream::new must be called only once");
+        ScancodeStream { _private: () }
+    }
+
+    /// Returns a future that resolves to the next scancode.
+    pub fn next(&mut self) -> NextScancode {
+        NextScancode { stream: self }
+    }
+
+    fn poll_next(&mut self, waker: &LocalWaker) -> Poll<u8> {
+        // the interrupt must not run between the check and storing the waker,
+        // or its wake-up would be lost
+        interrupts::without_interrupts(|| match SCANCODES.lock().pop() {
+            Some(scancode) => {
+                WAKER.lock().take();
+                Poll::Ready(scancode)
+            }
+            None => {
+                *WAKER.lock() = Some(waker.clone().into_waker());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/// The future returned by `ScancodeStream::next`.
+pub struct NextScancode<'a> {
+    stream: &'a mut ScancodeStream,
+}
+
+impl<'a> Future for NextScancode<'a> {
+    type Output = u8;
+
+    fn poll(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<u8> {
+        self.stream.poll_next(waker)
+    }
+}
+
+/// Returns the oldest typed character that wasn't read yet. The characters
+/// are collected by the `PrintKeypresses` task, if the buffer is full new
+/// ones are dropped.
+pub fn read_char() -> Option<char> {
+    // threads read it, so don't get preempted while holding the lock
+    interrupts::without_interrupts(|| CHARACTERS.lock().pop())
+}
+
+/// A task that echoes the typed characters to the screen and queues them for
+/// `read_char`.
+pub struct PrintKeypresses {
+    scancodes: ScancodeStream,
+}
+
+pub fn print_keypresses() -> PrintKeypresses {
+    PrintKeypresses {
+        scancodes: ScancodeStream::new(),
+    }
+}
+
+impl Future for PrintKeypresses {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<()> {
+        let dropped = DROPPED_SCANCODES.swap(0, Ordering::SeqCst);
+        if dropped > 0 {
+            println!("WARNING: scancode queue full, dropped {} scancodes", dropped);
+        }
+        while let Poll::Ready(scancode) = self.scancodes.poll_next(waker) {
+            let input = keyboard::decode(scancode);
+            if let Some(input) = input {
+                interrupts::without_interrupts(|| CHARACTERS.lock().push(input));
+            }
+            match input {
+                Some('\r') => println!(""),
+                Some('\t') => print!("    "),
+                Some('\0') => vga_buffer::backspace(),
+                Some('\x1B') => {} // TODO ESC
+                Some(input) => print!("{}", input),
+                None => {}
+            }
+        }
+        Poll::Pending
+    }
+}
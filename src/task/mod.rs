@@ -0,0 +1,53 @@
+//! A cooperative `no_std` async runtime. Tasks are futures that are polled by
+//! the `Executor` when their waker is called, e.g. from an interrupt handler
+//! through the `keyboard` and `timer` primitives.
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{LocalWaker, Poll};
+
+pub mod executor;
+pub mod keyboard;
+pub mod ring_buffer;
+pub mod timer;
+
+pub use self::executor::Executor;
+
+/// Identifies a task, also the data of its waker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(usize);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
+/// A future that runs to completion on an `Executor`.
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl Task {
+    pub fn new<F>(future: F) -> Task
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Task {
+            id: TaskId::new(),
+            future: Box::pinned(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, waker: &LocalWaker) -> Poll<()> {
+        self.future.as_mut().poll(waker)
+    }
+}
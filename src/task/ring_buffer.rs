@@ -0,0 +1,64 @@
+/// Number of elements a `RingBuffer` holds
+pub const CAPACITY: usize = 128;
+
+/// A fixed-size FIFO queue. It never allocates, so interrupt handlers can
+/// push to it.
+pub struct RingBuffer<T: Copy> {
+    buffer: [T; CAPACITY],
+    /// Index of the oldest element
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy> RingBuffer<T> {
+    /// Creates an empty buffer, `fill` is only used to initialize the slots.
+    pub fn new(fill: T) -> RingBuffer<T> {
+        RingBuffer {
+            buffer: [fill; CAPACITY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends `value`. Returns `false` and drops it if the buffer is full.
+    pub fn push(&mut self, value: T) -> bool {
+        if self.len == CAPACITY {
+            return false;
+        }
+        self.buffer[(self.head + self.len) % CAPACITY] = value;
+        self.len += 1;
+        true
+    }
+
+    /// Removes and returns the oldest element.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = self.buffer[self.head];
+        self.head = (self.head + 1) % CAPACITY;
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+
+    kernel_test!(ring_buffer_is_fifo_and_bounded {
+        let mut buffer = RingBuffer::new(0u8);
+        for i in 0..CAPACITY {
+            assert!(buffer.push(i as u8));
+        }
+        assert!(!buffer.push(0));
+        for i in 0..CAPACITY {
+            assert_eq!(buffer.pop(), Some(i as u8));
+        }
+        assert_eq!(buffer.pop(), None);
+    });
+}
@@ -0,0 +1,104 @@
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{LocalWaker, Poll, Waker};
+use core::time::Duration;
+use interrupts;
+use spin::Mutex;
+use time;
+
+/// A pending `Sleep`: its ID, its deadline (uptime in nanoseconds) and the
+/// waker to call when the deadline is reached
+struct Sleeper {
+    id: usize,
+    deadline: u64,
+    waker: Waker,
+}
+
+lazy_static! {
+    /// The pending `Sleep`s. Only `Sleep::poll` pushes, so the timer
+    /// interrupt doesn't allocate.
+    static ref SLEEPERS: Mutex<Vec<Sleeper>> = Mutex::new(Vec::new());
+}
+
+/// A future that resolves once the uptime reaches a deadline.
+pub struct Sleep {
+    id: usize,
+    deadline: u64,
+    /// Set once the sleep has an entry in `SLEEPERS`
+    registered: bool,
+}
+
+/// Returns a future that resolves after at least `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    Sleep {
+        id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+        deadline: time::deadline_after(duration),
+        registered: false,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<()> {
+        if time::uptime() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let waker = waker.clone().into_waker();
+        let (id, deadline) = (self.id, self.deadline);
+        let registered = self.registered;
+        interrupts::without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            // a later poll only replaces the waker of the existing entry
+            let index = if registered {
+                sleepers.iter().position(|sleeper| sleeper.id == id)
+            } else {
+                None
+            };
+            match index {
+                Some(index) => sleepers[index].waker = waker,
+                None => sleepers.push(Sleeper {
+                    id: id,
+                    deadline: deadline,
+                    waker: waker,
+                }),
+            }
+        });
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    /// Removes the entry of a sleep that is dropped before its deadline.
+    fn drop(&mut self) {
+        if !self.registered {
+            return;
+        }
+        let id = self.id;
+        interrupts::without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            if let Some(index) = sleepers.iter().position(|sleeper| sleeper.id == id) {
+                sleepers.swap_remove(index);
+            }
+        });
+    }
+}
+
+/// Wakes the sleeps whose deadline passed. Called from the timer interrupt.
+pub fn tick() {
+    let now = time::uptime();
+    let mut sleepers = SLEEPERS.lock();
+    let mut index = 0;
+    while index < sleepers.len() {
+        if sleepers[index].deadline <= now {
+            sleepers.swap_remove(index).waker.wake();
+        } else {
+            index += 1;
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use core::slice;
+use power;
+use {exit_qemu, QemuExitCode};
+
+/// A kernel test registered through `kernel_test!`.
+pub struct KernelTest {
+    pub name: &'static str,
+    pub func: fn(),
+}
+
+#[macro_export]
+/// Registers a kernel test. The tests are collected in the `.kernel_tests`
+/// linker section and run at boot by `run_tests`.
+///
+/// ```ignore
+/// kernel_test!(addition {
+///     assert_eq!(1 + 1, 2);
+/// });
+/// ```
+macro_rules! kernel_test {
+    ($name:ident $body:block) => {
+        #[allow(non_upper_case_globals)]
+        #[link_section = ".kernel_tests"]
+        #[used]
+        static $name: $crate::test_runner::KernelTest = $crate::test_runner::KernelTest {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            func: {
+                fn test() $body
+                test
+            },
+        };
+    };
+}
+
+extern "C" {
+    // defined in the linker script
+    static __kernel_tests_start: KernelTest;
+    static __kernel_tests_end: KernelTest;
+}
+
+/// Returns all registered kernel tests.
+fn tests() -> &'static [KernelTest] {
+    unsafe {
+        let start = &__kernel_tests_start as *const KernelTest;
+        let end = &__kernel_tests_end as *const KernelTest;
+        let count = (end as usize - start as usize) / ::core::mem::size_of::<KernelTest>();
+        slice::from_raw_parts(start, count)
+    }
+}
+
+/// Runs all kernel tests, reports the results over the serial port and exits
+/// QEMU. Without the `isa-debug-exit` device, e.g. on real hardware, the
+/// machine is powered off instead. A failing test panics, which makes the
+/// panic handler report the failure and exit QEMU with
+/// `QemuExitCode::Failed`.
+pub fn run_tests() -> ! {
+    let tests = tests();
+    serial_println!("running {} kernel tests", tests.len());
+    for test in tests {
+        serial_print!("{} ... ", test.name);
+        (test.func)();
+        serial_println!("[ok]");
+    }
+    serial_println!("all kernel tests passed");
+
+    unsafe {
+        exit_qemu(QemuExitCode::Success);
+    }
+    power::shutdown()
+}
@@ -0,0 +1,445 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use gdt;
+use interrupts;
+use memory::{self, AddressSpace, Stack};
+use spin::Mutex;
+use time;
+use x86_64;
+use KERNEL_STACK_PAGES;
+
+/// Maximum number of threads, including finished ones that weren't joined
+const MAX_THREADS: usize = 64;
+/// Number of timer ticks a thread runs before it is preempted
+const TIME_SLICE_TICKS: usize = 10;
+
+/// The value of RFLAGS a new thread starts with: interrupts disabled, like in
+/// the timer interrupt that usually switches to it (bit 1 is reserved)
+const INITIAL_RFLAGS: usize = 0x2;
+
+extern "C" {
+    // defined in `context_switch.asm`
+    fn switch_context(old_stack_pointer: *mut usize, new_stack_pointer: usize);
+    fn thread_start();
+}
+
+/// Identifies a thread. The boot thread, which continues `kernel_main`, has
+/// the ID 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadId(usize);
+
+impl ThreadId {
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    /// Waits until the uptime reaches the given nanoseconds
+    Sleeping(u64),
+    /// Waits until the given thread finished
+    Joining(ThreadId),
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    state: State,
+    /// The saved stack pointer while the thread isn't running
+    stack_pointer: usize,
+    /// The end of the kernel stack, where interrupts and system calls from
+    /// ring 3 start
+    stack_top: usize,
+    /// `None` for the boot thread, whose stack is owned by `rust_main`
+    stack: Option<Stack>,
+    /// `None` for kernel threads, which run with the kernel's page table
+    address_space: Option<AddressSpace>,
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack.take() {
+            memory::free_stack(stack);
+        }
+    }
+}
+
+/// The run queue and all threads of the bootstrap processor.
+struct Scheduler {
+    /// Boxed, so that `switch_context` can store the stack pointer in place
+    threads: Vec<Box<Thread>>,
+    /// Ready threads in the order they run. The capacity is reserved up
+    /// front, because the timer interrupt must not allocate.
+    run_queue: Vec<ThreadId>,
+    current: ThreadId,
+    /// Runs if no other thread is ready, it is never in the run queue
+    idle: ThreadId,
+    /// Ticks the current thread ran since it was switched to
+    slice_ticks: usize,
+}
+
+/// Locked with interrupts disabled, the timer interrupt switches threads.
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+/// ID of the running thread, readable without locking the scheduler
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// The ID of the next thread, 0 is the boot thread
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+impl Scheduler {
+    fn thread_mut(&mut self, id: ThreadId) -> Option<&mut Box<Thread>> {
+        self.threads.iter_mut().find(|thread| thread.id == id)
+    }
+
+    /// Sets the state of the current thread and chooses the next one. Returns
+    /// where to save the stack pointer of the current thread and the stack
+    /// pointer to continue with, or `None` if the current thread continues.
+    fn switch(&mut self, state: State) -> Option<(*mut usize, usize)> {
+        let current = self.current;
+        let is_idle = current == self.idle;
+        {
+            let thread = self.thread_mut(current).unwrap();
+            thread.state = if state == State::Running { State::Ready } else { state };
+        }
+        if state == State::Running && !is_idle {
+            self.run_queue.push(current);
+        }
+
+        let next = if self.run_queue.is_empty() {
+            self.idle
+        } else {
+            self.run_queue.remove(0)
+        };
+        self.slice_ticks = 0;
+        self.thread_mut(next).unwrap().state = State::Running;
+        if next == current {
+            return None;
+        }
+
+        self.current = next;
+        CURRENT.store(next.0, Ordering::SeqCst);
+        let (new_stack_pointer, new_stack_top, page_table) = {
+            let thread = self.thread_mut(next).unwrap();
+            let page_table = match thread.address_space {
+                Some(ref address_space) => address_space.page_table_address(),
+                None => memory::kernel_page_table(),
+            };
+            (thread.stack_pointer, thread.stack_top, page_table)
+        };
+        gdt::set_kernel_stack(new_stack_top);
+        // the kernel stacks are shared by all page tables
+        memory::switch_page_table(page_table);
+        let old_stack_pointer = &mut self.thread_mut(current).unwrap().stack_pointer as *mut usize;
+        Some((old_stack_pointer, new_stack_pointer))
+    }
+
+    /// Makes the sleeping threads whose time is up ready.
+    fn wake_sleepers(&mut self, now: u64) {
+        for thread in self.threads.iter_mut() {
+            if let State::Sleeping(deadline) = thread.state {
+                if deadline <= now {
+                    thread.state = State::Ready;
+                    self.run_queue.push(thread.id);
+                }
+            }
+        }
+    }
+
+    /// Makes the threads that wait for `id` ready.
+    fn wake_joiners(&mut self, id: ThreadId) {
+        for thread in self.threads.iter_mut() {
+            if thread.state == State::Joining(id) {
+                thread.state = State::Ready;
+                self.run_queue.push(thread.id);
+            }
+        }
+    }
+
+    /// Removes a finished thread, so that it can be dropped outside of the
+    /// lock.
+    fn take_finished(&mut self) -> Option<Box<Thread>> {
+        let current = self.current;
+        let index = self
+            .threads
+            .iter()
+            .position(|thread| thread.state == State::Finished && thread.id != current)?;
+        Some(self.threads.swap_remove(index))
+    }
+}
+
+/// Makes the running code the boot thread and creates the idle thread. Must
+/// be called once, after `memory::init` and the heap. `boot_stack_top` is the
+/// end of the stack the boot thread runs on.
+pub fn init(boot_stack_top: usize) {
+    assert_has_not_been_called!("thread::init must be called only once");
+
+    let boot_thread = Box::new(Thread {
+        id: ThreadId(0),
+        state: State::Running,
+        stack_pointer: 0,
+        stack_top: boot_stack_top,
+        stack: None,
+        address_space: None,
+    });
+    let idle_thread = new_thread(idle, None);
+    let mut scheduler = Scheduler {
+        threads: Vec::with_capacity(MAX_THREADS),
+        run_queue: Vec::with_capacity(MAX_THREADS),
+        current: boot_thread.id,
+        idle: idle_thread.id,
+        slice_ticks: 0,
+    };
+    scheduler.threads.push(boot_thread);
+    scheduler.threads.push(idle_thread);
+
+    interrupts::without_interrupts(|| {
+        *SCHEDULER.lock() = Some(scheduler);
+    });
+}
+
+/// Creates a thread with a fresh stack that starts with `thread_main::<F>`.
+fn new_thread<F>(f: F, address_space: Option<AddressSpace>) -> Box<Thread>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let stack = memory::alloc_stack(KERNEL_STACK_PAGES).expect("could not allocate thread stack");
+
+    // the initial stack as `switch_context` expects it, thread_start
+    // passes r12 to the function in r13
+    let initial_stack: [usize; 8] = [
+        0,                                   // r15
+        0,                                   // r14
+        thread_main::<F> as usize,           // r13
+        Box::into_raw(Box::new(f)) as usize, // r12
+        0,                                   // rbp
+        0,                                   // rbx
+        INITIAL_RFLAGS,
+        thread_start as usize,               // return address
+    ];
+    let stack_pointer = stack.top() - mem::size_of_val(&initial_stack);
+    unsafe {
+        ptr::write(stack_pointer as *mut [usize; 8], initial_stack);
+    }
+
+    Box::new(Thread {
+        id: ThreadId(NEXT_ID.fetch_add(1, Ordering::SeqCst)),
+        state: State::Ready,
+        stack_pointer: stack_pointer,
+        stack_top: stack.top(),
+        stack: Some(stack),
+        address_space: address_space,
+    })
+}
+
+/// The entry function of the threads created by `spawn`.
+extern "C" fn thread_main<F>(f: *mut F) -> !
+where
+    F: FnOnce(),
+{
+    // the first switch to a thread happens with interrupts disabled
+    x86_64::instructions::interrupts::enable();
+    let f = unsafe { Box::from_raw(f) };
+    (*f)();
+    exit();
+}
+
+fn idle() {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Starts a new thread that runs `f`. Panics if there are already
+/// `MAX_THREADS` threads.
+pub fn spawn<F>(f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_thread(f, None)
+}
+
+/// Starts a new thread that runs `f` with the page table of
+/// `address_space`, which is freed when the thread finished. Panics if there
+/// are already `MAX_THREADS` threads.
+pub fn spawn_in<F>(address_space: AddressSpace, f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_thread(f, Some(address_space))
+}
+
+fn spawn_thread<F>(f: F, address_space: Option<AddressSpace>) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    reap();
+    // allocate before locking, the timer interrupt needs the lock
+    let thread = new_thread(f, address_space);
+    let id = thread.id;
+    let rejected = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("thread::init must be called first");
+        if scheduler.threads.len() >= MAX_THREADS {
+            return Some(thread);
+        }
+        scheduler.threads.push(thread);
+        scheduler.run_queue.push(id);
+        None
+    });
+    // the rejected thread is dropped outside of the lock, like in `reap`
+    assert!(rejected.is_none(), "too many threads");
+    id
+}
+
+/// Drops the finished threads, which frees their stacks and address spaces.
+/// They are dropped one at a time outside of the scheduler lock, since that
+/// needs the memory controller and the heap.
+fn reap() {
+    loop {
+        let finished = interrupts::without_interrupts(|| {
+            SCHEDULER.lock().as_mut().and_then(|scheduler| scheduler.take_finished())
+        });
+        match finished {
+            Some(thread) => mem::drop(thread),
+            None => break,
+        }
+    }
+}
+
+/// Returns the ID of the running thread.
+pub fn current() -> ThreadId {
+    ThreadId(CURRENT.load(Ordering::SeqCst))
+}
+
+/// Gives up the rest of the time slice to the next ready thread.
+pub fn yield_now() {
+    block_current(State::Running);
+}
+
+/// Blocks the running thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    let deadline = time::deadline_after(duration);
+    block_current(State::Sleeping(deadline));
+}
+
+/// Blocks the running thread until the thread `id` finished. Returns
+/// immediately if it finished already.
+pub fn join(id: ThreadId) {
+    assert!(id != current(), "a thread can't join itself");
+    loop {
+        let mut finished = false;
+        // checked in the same critical section as the switch to `Joining`,
+        // so that `exit` can't wake the joiners in between
+        block_current_if(|scheduler| {
+            finished = scheduler
+                .thread_mut(id)
+                .map(|thread| thread.state == State::Finished)
+                .unwrap_or(true);
+            if finished {
+                None
+            } else {
+                Some(State::Joining(id))
+            }
+        });
+        if finished {
+            reap();
+            return;
+        }
+    }
+}
+
+/// Ends the running thread. Its stack and address space are freed by a later
+/// `spawn` or `join`.
+pub fn exit() -> ! {
+    block_current_if(|scheduler| {
+        let id = scheduler.current;
+        scheduler.wake_joiners(id);
+        Some(State::Finished)
+    });
+    unreachable!("finished thread continued");
+}
+
+/// Advances the time slice of the running thread and switches to the next
+/// thread if it is used up. Called from the timer interrupt.
+pub fn tick() {
+    let preempt = match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => {
+            scheduler.wake_sleepers(time::uptime());
+            scheduler.slice_ticks += 1;
+            let idle = scheduler.current == scheduler.idle;
+            (idle && !scheduler.run_queue.is_empty()) || scheduler.slice_ticks >= TIME_SLICE_TICKS
+        }
+        None => false,
+    };
+    if preempt {
+        block_current(State::Running);
+    }
+}
+
+/// Puts the running thread into `state` and switches to the next thread.
+/// `State::Running` keeps it ready.
+fn block_current(state: State) {
+    block_current_if(|_| Some(state));
+}
+
+/// Calls `f` with the scheduler locked and, if it returns a state, puts the
+/// running thread into it and switches to the next thread. Both happen in the
+/// same critical section, so no wakeup can get lost in between.
+fn block_current_if<F>(f: F)
+where
+    F: FnOnce(&mut Scheduler) -> Option<State>,
+{
+    interrupts::without_interrupts(|| {
+        // the lock must be released before switching, the next thread takes
+        // it again
+        let switch = match SCHEDULER.lock().as_mut() {
+            Some(scheduler) => f(scheduler).and_then(|state| scheduler.switch(state)),
+            None => None,
+        };
+        if let Some((old_stack_pointer, new_stack_pointer)) = switch {
+            unsafe { switch_context(old_stack_pointer, new_stack_pointer) };
+        }
+    });
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    kernel_test!(spawn_and_join {
+        let threads = [
+            spawn(|| { COUNTER.fetch_add(1, Ordering::SeqCst); }),
+            spawn(|| { COUNTER.fetch_add(1, Ordering::SeqCst); }),
+        ];
+        for &thread in threads.iter() {
+            join(thread);
+        }
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 2);
+    });
+
+    static ORDER: AtomicUsize = AtomicUsize::new(0);
+
+    kernel_test!(sleeping_thread_wakes_up_later {
+        let sleeper = spawn(|| {
+            sleep(Duration::from_millis(20));
+            ORDER.store(ORDER.load(Ordering::SeqCst) * 10 + 2, Ordering::SeqCst);
+        });
+        let worker = spawn(|| {
+            ORDER.store(ORDER.load(Ordering::SeqCst) * 10 + 1, Ordering::SeqCst);
+        });
+        join(sleeper);
+        join(worker);
+        assert_eq!(ORDER.load(Ordering::SeqCst), 12);
+    });
+}
@@ -0,0 +1,33 @@
+use core::time::Duration;
+use interrupts::{self, apic};
+use time;
+
+/// Time the APIC timer counts down against the current tick source
+const CALIBRATION_TIME_MS: u64 = 10;
+
+/// Replaces the current tick source with the local APIC timer, firing the
+/// timer interrupt `frequency` times per second. The APIC timer frequency
+/// depends on the bus clock, so it is first measured against the running
+/// tick source (usually the PIT). Returns `false` if the APICs are not
+/// enabled, see `interrupts::enable_apic`.
+pub fn init(frequency: usize) -> bool {
+    assert!(frequency > 0, "APIC timer frequency must not be zero");
+    if !interrupts::apic_enabled() {
+        return false;
+    }
+
+    apic::start_timer(u32::max_value(), None);
+    time::sleep(Duration::from_millis(CALIBRATION_TIME_MS));
+    let counts = u64::from(u32::max_value() - apic::timer_count());
+    let counts_per_second = counts * 1000 / CALIBRATION_TIME_MS;
+
+    interrupts::without_interrupts(|| {
+        // the APIC timer uses the vector of the PIT, so the handler of the
+        // timer IRQ stays the same
+        apic::mask_irq(interrupts::TIMER_IRQ);
+        let initial_count = counts_per_second / frequency as u64;
+        apic::start_timer(initial_count as u32, Some(interrupts::TIMER_INTERRUPT_ID));
+        time::set_tick_frequency(frequency);
+    });
+    true
+}
@@ -0,0 +1,188 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use interrupts;
+use spin::Mutex;
+use x86_64;
+
+pub mod apic_timer;
+pub mod pit;
+pub mod rtc;
+
+pub use self::rtc::DateTime;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MAX_TIMERS: usize = 32;
+
+/// Number of ticks since the tick source was started
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+/// Ticks per second of the current tick source
+static TICK_FREQUENCY: AtomicUsize = AtomicUsize::new(0);
+/// Tick count and uptime in nanoseconds when the tick frequency last changed
+static FREQUENCY_CHANGE_TICKS: AtomicUsize = AtomicUsize::new(0);
+static FREQUENCY_CHANGE_UPTIME: AtomicUsize = AtomicUsize::new(0);
+/// Unix timestamp of uptime zero, set by `init_wall_clock`
+static BOOT_TIMESTAMP: AtomicUsize = AtomicUsize::new(0);
+
+/// A function that is called from the timer interrupt.
+pub type TimerCallback = fn();
+
+/// Identifies a scheduled timer, see `cancel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(usize);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    /// Tick at which the callback is called next
+    deadline: usize,
+    /// Ticks between two calls of a periodic timer
+    period: Option<usize>,
+    callback: TimerCallback,
+}
+
+static TIMERS: Mutex<[Option<Timer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+
+/// Sets the number of ticks per second. Called by the tick source, with
+/// interrupts disabled if it replaces a running one. The uptime continues
+/// from its current value.
+pub fn set_tick_frequency(frequency: usize) {
+    FREQUENCY_CHANGE_UPTIME.store(uptime() as usize, Ordering::SeqCst);
+    FREQUENCY_CHANGE_TICKS.store(ticks(), Ordering::SeqCst);
+    TICK_FREQUENCY.store(frequency, Ordering::SeqCst);
+}
+
+/// Advances the time by one tick and runs the due timers. Called from the
+/// interrupt handler of the tick source.
+pub fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+
+    // collect the due callbacks first, so that they can (re)schedule timers
+    let mut due: [Option<TimerCallback>; MAX_TIMERS] = [None; MAX_TIMERS];
+    {
+        let mut timers = TIMERS.lock();
+        for (slot, due) in timers.iter_mut().zip(due.iter_mut()) {
+            if let Some(mut timer) = *slot {
+                if timer.deadline <= now {
+                    *due = Some(timer.callback);
+                    *slot = timer.period.map(|period| {
+                        timer.deadline = now + period;
+                        timer
+                    });
+                }
+            }
+        }
+    }
+
+    for callback in due.iter().filter_map(|callback| *callback) {
+        callback();
+    }
+}
+
+/// Returns the number of ticks since the tick source was started.
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Returns the ticks per second of the current tick source.
+pub fn tick_frequency() -> usize {
+    TICK_FREQUENCY.load(Ordering::SeqCst)
+}
+
+/// Returns the time since the tick source was started in nanoseconds.
+pub fn uptime() -> u64 {
+    let frequency = tick_frequency() as u64;
+    if frequency == 0 {
+        return 0;
+    }
+    let ticks = (ticks() - FREQUENCY_CHANGE_TICKS.load(Ordering::SeqCst)) as u64;
+    FREQUENCY_CHANGE_UPTIME.load(Ordering::SeqCst) as u64 + ticks * NANOS_PER_SEC / frequency
+}
+
+/// Reads the RTC once and anchors the wall clock to the tick source, so that
+/// `now` doesn't need to access the CMOS. Must be called after the tick
+/// source is started.
+pub fn init_wall_clock() -> DateTime {
+    let date_time = rtc::read();
+    let boot_timestamp = date_time.unix_timestamp() - uptime() / NANOS_PER_SEC;
+    BOOT_TIMESTAMP.store(boot_timestamp as usize, Ordering::SeqCst);
+    date_time
+}
+
+/// Returns the seconds since 1970-01-01 00:00:00 UTC.
+pub fn unix_timestamp() -> u64 {
+    BOOT_TIMESTAMP.load(Ordering::SeqCst) as u64 + uptime() / NANOS_PER_SEC
+}
+
+/// Returns the current date and time.
+pub fn now() -> DateTime {
+    DateTime::from_unix_timestamp(unix_timestamp())
+}
+
+/// Halts the CPU until `duration` has passed. Interrupts must be enabled.
+pub fn sleep(duration: Duration) {
+    let deadline = deadline_after(duration);
+    while uptime() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Calls `callback` from the timer interrupt once after `delay`. Returns
+/// `None` if all timer slots are in use.
+pub fn schedule_once(delay: Duration, callback: TimerCallback) -> Option<TimerId> {
+    schedule(duration_as_ticks(delay), None, callback)
+}
+
+/// Calls `callback` from the timer interrupt every `period`. Returns `None`
+/// if all timer slots are in use.
+pub fn schedule_periodic(period: Duration, callback: TimerCallback) -> Option<TimerId> {
+    let period = duration_as_ticks(period);
+    schedule(period, Some(period), callback)
+}
+
+/// Cancels the given timer. Does nothing if the timer already ran.
+pub fn cancel(id: TimerId) {
+    interrupts::without_interrupts(|| {
+        TIMERS.lock()[id.0] = None;
+    });
+}
+
+fn schedule(delay: usize, period: Option<usize>, callback: TimerCallback) -> Option<TimerId> {
+    // the timer interrupt locks the timers too
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let index = timers.iter().position(|slot| slot.is_none())?;
+        timers[index] = Some(Timer {
+            deadline: ticks() + delay,
+            period: period,
+            callback: callback,
+        });
+        Some(TimerId(index))
+    })
+}
+
+/// Converts the duration to nanoseconds, the unit of `uptime`. Saturates at
+/// `u64::MAX`, about 584 years, since durations can come from user programs.
+pub fn duration_as_nanos(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(NANOS_PER_SEC)
+        .saturating_add(u64::from(duration.subsec_nanos()))
+}
+
+/// Returns the `uptime` at which `duration` from now will have passed,
+/// saturating like `duration_as_nanos`.
+pub fn deadline_after(duration: Duration) -> u64 {
+    uptime().saturating_add(duration_as_nanos(duration))
+}
+
+/// Converts the duration to ticks, rounded up to at least one tick.
+fn duration_as_ticks(duration: Duration) -> usize {
+    let frequency = tick_frequency() as u64;
+    assert!(frequency != 0, "no tick source initialized");
+    let nanos = duration_as_nanos(duration);
+    let ticks = nanos.saturating_mul(frequency).saturating_add(NANOS_PER_SEC - 1) / NANOS_PER_SEC;
+    if ticks == 0 {
+        1
+    } else {
+        ticks as usize
+    }
+}
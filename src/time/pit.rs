@@ -0,0 +1,42 @@
+use cpuio::Port;
+use spin::Mutex;
+use time;
+
+/// Input frequency of the PIT in Hz
+const BASE_FREQUENCY: usize = 1_193_182;
+
+/// Channel 0, lobyte/hibyte access, mode 3 (square wave), binary counting
+const CHANNEL0_SQUARE_WAVE: u8 = 0b0011_0110;
+
+/// The I/O ports of the Programmable Interval Timer.
+struct Pit {
+    channel0: Port<u8>,
+    command: Port<u8>,
+}
+
+static PIT: Mutex<Pit> = Mutex::new(Pit {
+    channel0: unsafe { Port::new(0x40) },
+    command: unsafe { Port::new(0x43) },
+});
+
+/// Programs channel 0 of the PIT to fire the timer interrupt `frequency`
+/// times per second and makes it the tick source of the `time` module.
+/// Returns the frequency that is actually used, which differs slightly
+/// because the PIT divides its base frequency by an integer.
+pub fn init(frequency: usize) -> usize {
+    assert!(frequency > 0, "PIT frequency must not be zero");
+    let divisor = match BASE_FREQUENCY / frequency {
+        0 => 1,
+        divisor if divisor > 0xffff => 0xffff,
+        divisor => divisor,
+    };
+
+    let mut pit = PIT.lock();
+    pit.command.write(CHANNEL0_SQUARE_WAVE);
+    pit.channel0.write(divisor as u8);
+    pit.channel0.write((divisor >> 8) as u8);
+
+    let actual_frequency = BASE_FREQUENCY / divisor;
+    time::set_tick_frequency(actual_frequency);
+    actual_frequency
+}
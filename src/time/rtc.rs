@@ -0,0 +1,198 @@
+use core::fmt;
+use cpuio::Port;
+use spin::Mutex;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+/// Status A: an update of the time registers is in progress
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: the registers hold binary values instead of BCD
+const BINARY_MODE: u8 = 1 << 2;
+/// Status B: the hours are in 24-hour format
+const HOUR_24_MODE: u8 = 1 << 1;
+/// Hours register: PM flag in 12-hour format
+const HOUR_PM: u8 = 1 << 7;
+/// Index register: keep NMIs disabled while accessing the CMOS
+const NMI_DISABLE: u8 = 1 << 7;
+
+/// The I/O ports of the CMOS.
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+    index: unsafe { Port::new(0x70) },
+    data: unsafe { Port::new(0x71) },
+});
+
+impl Cmos {
+    /// Reads `register` with NMIs disabled and enables them again afterwards.
+    fn read_register(&mut self, register: u8) -> u8 {
+        self.index.write(NMI_DISABLE | register);
+        let value = self.data.read();
+        self.index.write(register);
+        value
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0
+    }
+
+    /// Reads the raw time registers after waiting for a running update.
+    fn read_raw(&mut self) -> [u8; 6] {
+        while self.update_in_progress() {}
+        [
+            self.read_register(SECONDS),
+            self.read_register(MINUTES),
+            self.read_register(HOURS),
+            self.read_register(DAY),
+            self.read_register(MONTH),
+            self.read_register(YEAR),
+        ]
+    }
+}
+
+/// A calendar date and time (UTC, as the RTC keeps it in QEMU).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the seconds since 1970-01-01 00:00:00.
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// Creates the date and time of the given seconds since 1970-01-01.
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86400) as i64;
+        let seconds_of_day = timestamp % 86400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Reads the current date and time from the RTC.
+pub fn read() -> DateTime {
+    let mut cmos = CMOS.lock();
+
+    // read until two reads agree, so that we don't get a value torn by an
+    // update that started in between
+    let mut raw = cmos.read_raw();
+    loop {
+        let again = cmos.read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status_b = cmos.read_register(STATUS_B);
+
+    let convert = |value: u8| {
+        if status_b & BINARY_MODE != 0 {
+            value
+        } else {
+            (value & 0x0f) + (value >> 4) * 10
+        }
+    };
+
+    let pm = raw[2] & HOUR_PM != 0;
+    let mut hour = convert(raw[2] & !HOUR_PM);
+    if status_b & HOUR_24_MODE == 0 {
+        // 12-hour format: 12 AM is midnight and 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        // the century register is not standardized, assume the 21st century
+        year: 2000 + convert(raw[5]) as u16,
+        month: convert(raw[4]),
+        day: convert(raw[3]),
+        hour: hour,
+        minute: convert(raw[1]),
+        second: convert(raw[0]),
+    }
+}
+
+/// Returns the number of days since 1970-01-01 of the given date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Returns the date of the given number of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+
+    kernel_test!(unix_timestamp_of_known_dates {
+        let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+        assert_eq!(epoch.unix_timestamp(), 0);
+        let leap_day = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 42 };
+        assert_eq!(leap_day.unix_timestamp(), 1_709_213_862);
+    });
+
+    kernel_test!(unix_timestamp_round_trip {
+        for &timestamp in &[0, 951_782_400, 1_709_213_862, 4_102_444_799] {
+            assert_eq!(DateTime::from_unix_timestamp(timestamp).unix_timestamp(), timestamp);
+        }
+    });
+}
@@ -0,0 +1,92 @@
+use core::slice;
+use elf;
+use gdt;
+use memory::{AddressSpace, EntryFlags, PAGE_SIZE};
+use thread::{self, ThreadId};
+
+/// The lower half from P4 entry 1 on belongs to user programs, everything
+/// below and the higher half belong to the kernel. The last page of the
+/// lower half is left out: a `syscall` at its end would save the
+/// non-canonical RIP `0x8000_0000_0000`, and `sysret` to it faults in ring 0.
+pub const USER_SPACE_START: usize = 0x0000_0080_0000_0000;
+pub const USER_SPACE_END: usize = 0x0000_7fff_ffff_f000;
+
+/// Where user programs are loaded
+pub const USER_CODE_ADDRESS: usize = USER_SPACE_START;
+/// The top of the user stack, the end of P4 entry 1
+pub const USER_STACK_TOP: usize = 0x0000_0100_0000_0000;
+/// Size of the user stack in pages
+pub const USER_STACK_PAGES: usize = 4;
+
+/// The RFLAGS of a program when it starts: interrupts enabled (bit 1 is
+/// reserved)
+const USER_RFLAGS: u64 = 0x202;
+
+extern "C" {
+    // defined in `user_program.asm`
+    static user_program_start: u8;
+    static user_program_end: u8;
+}
+
+/// Jumps to `entry` in ring 3 with the stack pointer `stack_top`. Interrupts
+/// and system calls of the program use the kernel stack of the current
+/// thread, which must not be the boot thread.
+pub fn enter(entry: usize, stack_top: usize) -> ! {
+    let selectors = gdt::selectors();
+    unsafe {
+        // the frame `iretq` expects: SS, RSP, RFLAGS, CS and RIP. The GS base
+        // of the program is swapped in with interrupts off, `iretq` enables
+        // them again.
+        asm!("cli
+              swapgs
+              push $0
+              push $1
+              push $2
+              push $3
+              push $4
+              iretq"
+             :: "r"(u64::from(selectors.user_data.0)), "r"(stack_top), "r"(USER_RFLAGS),
+                "r"(u64::from(selectors.user_code.0)), "r"(entry)
+             : "memory" : "intel", "volatile");
+    }
+    unreachable!();
+}
+
+/// Loads the program of `user_program.asm` into a new address space and runs
+/// it in a new thread.
+pub fn spawn_embedded_program() -> ThreadId {
+    let program = unsafe {
+        let start = &user_program_start as *const u8;
+        let size = &user_program_end as *const u8 as usize - start as usize;
+        slice::from_raw_parts(start, size)
+    };
+
+    let mut address_space = AddressSpace::new().expect("could not create an address space");
+    let mapped = address_space.map_region(USER_CODE_ADDRESS, program.len(), EntryFlags::empty());
+    assert!(mapped, "could not map the user program");
+    let copied = address_space.copy_to(USER_CODE_ADDRESS, program);
+    assert!(copied, "could not copy the user program");
+
+    let stack_size = USER_STACK_PAGES * PAGE_SIZE;
+    let mapped = address_space.map_region(
+        USER_STACK_TOP - stack_size,
+        stack_size,
+        EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+    );
+    assert!(mapped, "could not map the user stack");
+
+    thread::spawn_in(address_space, || {
+        enter(USER_CODE_ADDRESS, USER_STACK_TOP);
+    })
+}
+
+/// Loads the ELF executable `image` into a new address space and runs it in
+/// a new thread with the arguments `argv` and the environment `envp`.
+pub fn spawn_program(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<ThreadId, elf::Error> {
+    let mut address_space = AddressSpace::new().ok_or(elf::Error::OutOfMemory)?;
+    let program = elf::load(&mut address_space, image, argv, envp)?;
+
+    Ok(thread::spawn_in(address_space, move || {
+        enter(program.entry, program.stack_pointer);
+    }))
+}
@@ -172,3 +172,70 @@ pub fn backspace() {
         WRITER.lock().column_position -= 1;
     }
 }
+
+#[cfg(feature = "kernel-test")]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use core::mem;
+
+    /// Creates a writer on a leaked buffer instead of the VGA text buffer.
+    fn construct_writer() -> Writer {
+        let buffer: Box<Buffer> = Box::new(unsafe { mem::zeroed() });
+        Writer {
+            column_position: 0,
+            color_code: ColorCode::new(Color::Blue, Color::Magenta),
+            buffer: Box::leak(buffer),
+        }
+    }
+
+    fn empty_char() -> ScreenChar {
+        ScreenChar {
+            ascii_char: 0,
+            color_code: ColorCode(0),
+        }
+    }
+
+    kernel_test!(write_byte {
+        let mut writer = construct_writer();
+        writer.write_byte(b'X');
+        writer.write_byte(b'Y');
+
+        for (i, row) in writer.buffer.chars.iter().enumerate() {
+            for (j, screen_char) in row.iter().enumerate() {
+                let screen_char = screen_char.read();
+                if i == BUFFER_HEIGHT - 1 && j == 0 {
+                    assert_eq!(screen_char.ascii_char, b'X');
+                    assert_eq!(screen_char.color_code, writer.color_code);
+                } else if i == BUFFER_HEIGHT - 1 && j == 1 {
+                    assert_eq!(screen_char.ascii_char, b'Y');
+                    assert_eq!(screen_char.color_code, writer.color_code);
+                } else {
+                    assert_eq!(screen_char, empty_char());
+                }
+            }
+        }
+    });
+
+    kernel_test!(write_string_wraps_and_shifts_lines {
+        let mut writer = construct_writer();
+        for _ in 0..BUFFER_WIDTH {
+            writer.write_byte(b'a');
+        }
+        writer.write_string("b\nc\u{e4}");
+
+        let last = &writer.buffer.chars[BUFFER_HEIGHT - 1];
+        let second_last = &writer.buffer.chars[BUFFER_HEIGHT - 2];
+        let third_last = &writer.buffer.chars[BUFFER_HEIGHT - 3];
+        for screen_char in third_last.iter() {
+            assert_eq!(screen_char.read().ascii_char, b'a');
+        }
+        assert_eq!(second_last[0].read().ascii_char, b'b');
+        assert_eq!(second_last[1].read().ascii_char, b' ');
+        assert_eq!(last[0].read().ascii_char, b'c');
+        // non-ASCII chars are two bytes in UTF-8, both are replaced
+        assert_eq!(last[1].read().ascii_char, 0xfe);
+        assert_eq!(last[2].read().ascii_char, 0xfe);
+        assert_eq!(writer.column_position, 3);
+    });
+}